@@ -1,8 +1,15 @@
 use crate::deluge;
-use crate::soundfont::{Generator, LoopMode, SoundFont, Unit};
-use log::{info, warn};
+use crate::resample;
+use crate::soundfont::{
+    DestOper, Generator, LoopMode, ModList, ModularTransform, Modulator, Region, SampleWindow,
+    SoundFont, SourceDirection, SourceEnumeratorController, SourcePolarity, SourceType, Unit,
+    WavOptions,
+};
+use log::{info, log, warn, Level};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 macro_rules! get_zone_generator {
     ($zone:expr, $pattern:pat) => ({
@@ -17,347 +24,1629 @@ macro_rules! get_zone_generator {
     } as Option<Generator>)
 }
 
-pub fn soundfont_to_deluge(
-    sf: &SoundFont,
-    sample_folder: &Path,
-    ix: usize,
-    prefix: &str,
-) -> deluge::Sound {
-    let is_last = ix == sf.presets.len() - 1;
-    let preset = &sf.presets[ix];
-    info!("Preset: {}", preset.name);
-    let bag_start = preset.bag_index as usize;
-    let bag_end = if is_last {
-        sf.pbags.len()
-    } else {
-        let next_preset = &sf.presets[ix + 1];
-        next_preset.bag_index as usize
-    };
-    let mut instruments = vec![];
-    for bag_ix in bag_start..bag_end {
-        let is_last = ix == sf.pbags.len() - 1;
-        let bag = &sf.pbags[bag_ix];
-        let gen_start = bag.gen_ndx as usize;
-        let gen_end = if is_last {
-            sf.pgens.len()
-        } else {
-            let next_bag = &sf.pbags[bag_ix + 1];
-            next_bag.gen_ndx as usize
-        };
-        for gen_ix in gen_start..gen_end {
-            let gen = &sf.pgens[gen_ix];
-            if let Generator::Instrument(index) = gen {
-                let mut gens = get_instrument_zones(sf, *index as usize);
-                // Sort on key range
-                gens.sort_by(|a, b| {
-                    let a_r = if let Some(Generator::KeyRange(low, high)) =
-                        get_zone_generator!(a, Generator::KeyRange(_, _))
-                    {
-                        (low, high)
-                    } else {
-                        (0, 127)
-                    };
-                    let b_r = if let Some(Generator::KeyRange(low, high)) =
-                        get_zone_generator!(b, Generator::KeyRange(_, _))
-                    {
-                        (low, high)
-                    } else {
-                        (0, 127)
-                    };
-                    a_r.cmp(&b_r)
-                });
-                instruments.push(gens);
-            }
+// Percussion presets are in bank 128
+const DRUM_BANK: u16 = 128;
+
+/// Which velocity layer to keep when a preset has zones that split on velocity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VelocityLayer {
+    Loudest,
+    Softest,
+    /// The layer that plays at this velocity
+    Nominal(u8),
+    /// The layer with the widest velocity range
+    Widest,
+}
+
+impl Default for VelocityLayer {
+    fn default() -> Self {
+        VelocityLayer::Nominal(100)
+    }
+}
+
+impl FromStr for VelocityLayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "loudest" => Ok(VelocityLayer::Loudest),
+            "softest" => Ok(VelocityLayer::Softest),
+            "widest" => Ok(VelocityLayer::Widest),
+            _ => match s.parse::<u8>() {
+                Ok(v) if v <= 127 => Ok(VelocityLayer::Nominal(v)),
+                _ => Err(format!(
+                    "expected loudest, softest, widest or a velocity 0-127, got {}",
+                    s
+                )),
+            },
         }
     }
-    for inst in &instruments {
-        info!("instrument: {:?}", inst);
+}
+
+impl VelocityLayer {
+    /// The velocity that selects this layer among the regions
+    fn velocity(&self, regions: &[Region]) -> u8 {
+        let v = match self {
+            VelocityLayer::Loudest => regions.iter().map(|r| r.vel_range.1).max().unwrap_or(127),
+            VelocityLayer::Softest => regions.iter().map(|r| r.vel_range.0).min().unwrap_or(0),
+            VelocityLayer::Nominal(v) => *v,
+            VelocityLayer::Widest => regions
+                .iter()
+                .max_by_key(|r| (r.vel_range.1 - r.vel_range.0, r.vel_range.1))
+                .map_or(127, |r| r.vel_range.0 + (r.vel_range.1 - r.vel_range.0) / 2),
+        };
+        // If no region plays at the velocity, use the closest one that does
+        regions
+            .iter()
+            .map(|r| v.clamp(r.vel_range.0, r.vel_range.1))
+            .min_by_key(|x| (*x as i32 - v as i32).abs())
+            .unwrap_or(v)
     }
-    // Map instruments to oscs
-    let mut oscs = vec![];
-    let mut attack_time = vec![];
-    let mut decay_time = vec![];
-    let mut sustain_vol = vec![];
-    let mut release_time = vec![];
-    for (instrument_ix, zones) in instruments.iter().enumerate() {
-        let mut osc = vec![];
-        for (zone_ix, zone) in zones.iter().enumerate() {
-            if let Some(g) = get_zone_generator!(zone, Generator::AttackVolEnv(_)) {
-                if let Some(Unit::Seconds(s)) = g.value() {
-                    attack_time.push(s);
-                }
-            }
-            if let Some(g) = get_zone_generator!(zone, Generator::DecayVolEnv(_)) {
-                if let Some(Unit::Seconds(s)) = g.value() {
-                    decay_time.push(s);
+}
+
+fn velocity_layer(regions: &[Region], velocity: u8) -> Vec<Region> {
+    regions
+        .iter()
+        .filter(|r| r.vel_range.0 <= velocity && velocity <= r.vel_range.1)
+        .cloned()
+        .collect()
+}
+
+/// What to do with the layers of a preset that don't fit in the two oscs of a deluge sound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExtraLayers {
+    #[default]
+    Drop,
+    /// Write more sounds, "Preset A", "Preset B" etc
+    Sounds,
+    /// Write the sounds as rows of a kit
+    Kit,
+}
+
+impl FromStr for ExtraLayers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(ExtraLayers::Drop),
+            "sounds" => Ok(ExtraLayers::Sounds),
+            "kit" => Ok(ExtraLayers::Kit),
+            _ => Err(format!("expected drop, sounds or kit, got {}", s)),
+        }
+    }
+}
+
+/// The sample rate of the written samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleRate {
+    /// Keep the rate if the deluge can play it, otherwise resample to 44.1 kHz
+    #[default]
+    Keep,
+    Rate(u32),
+}
+
+impl FromStr for SampleRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(SampleRate::Keep),
+            "44100" => Ok(SampleRate::Rate(44100)),
+            "48000" => Ok(SampleRate::Rate(48000)),
+            _ => Err(format!("expected keep, 44100 or 48000, got {}", s)),
+        }
+    }
+}
+
+impl SampleRate {
+    /// The rate to write a sample with the given rate at
+    fn output_rate(&self, rate: u32) -> u32 {
+        match self {
+            SampleRate::Keep if resample::DELUGE_RATES.contains(&rate) => rate,
+            SampleRate::Keep => 44100,
+            SampleRate::Rate(rate) => *rate,
+        }
+    }
+}
+
+/// How the envelope values of the zones are combined into one deluge envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvelopeAggregation {
+    #[default]
+    Mean,
+    Median,
+    /// Mean of the logarithms, i.e. of timecents for times
+    LogMean,
+    /// Mean weighted by the number of keys each zone covers
+    KeyWeighted,
+    /// The value of the zone nearest middle C
+    MiddleC,
+    Maximum,
+}
+
+impl FromStr for EnvelopeAggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(EnvelopeAggregation::Mean),
+            "median" => Ok(EnvelopeAggregation::Median),
+            "log-mean" => Ok(EnvelopeAggregation::LogMean),
+            "key-weighted" => Ok(EnvelopeAggregation::KeyWeighted),
+            "middle-c" => Ok(EnvelopeAggregation::MiddleC),
+            "max" => Ok(EnvelopeAggregation::Maximum),
+            _ => Err(format!(
+                "expected mean, median, log-mean, key-weighted, middle-c or max, got {}",
+                s
+            )),
+        }
+    }
+}
+
+impl EnvelopeAggregation {
+    /// Combines the values of the regions, 0 if there are none
+    fn aggregate(&self, values: &[(&Region, f32)]) -> f32 {
+        if values.is_empty() {
+            return 0.0;
+        }
+        match self {
+            EnvelopeAggregation::Mean => mean(&values.iter().map(|(_, v)| *v).collect::<Vec<_>>()),
+            EnvelopeAggregation::Median => {
+                let mut sorted: Vec<f32> = values.iter().map(|(_, v)| *v).collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 0 {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
                 }
             }
-            if let Some(g) = get_zone_generator!(zone, Generator::SustainVolEnv(_)) {
-                if let Some(Unit::Level(lvl)) = g.value() {
-                    sustain_vol.push(lvl);
-                }
+            EnvelopeAggregation::LogMean => {
+                // Times are never zero, they are at least 1 ms
+                let logs: Vec<f32> = values.iter().map(|(_, v)| v.max(0.001).ln()).collect();
+                mean(&logs).exp()
             }
-            if let Some(g) = get_zone_generator!(zone, Generator::ReleaseVolEnv(_)) {
-                if let Some(Unit::Seconds(s)) = g.value() {
-                    release_time.push(s);
-                }
+            EnvelopeAggregation::KeyWeighted => {
+                let keys =
+                    |r: &Region| (r.key_range.1 as f32 - r.key_range.0 as f32 + 1.0).max(1.0);
+                let total: f32 = values.iter().map(|(r, _)| keys(r)).sum();
+                values.iter().map(|(r, v)| keys(r) * v).sum::<f32>() / total
             }
-            if let Generator::KeyRange(low, high) =
-                get_zone_generator!(zone, Generator::KeyRange(_, _))
-                    .unwrap_or(Generator::KeyRange(0, 127))
-            {
-                let mut sample_name = None;
-                let mut original_key = None;
-                let mut scale = (1, 1);
-                if let Some(Generator::SampleID(sample_id)) =
-                    get_zone_generator!(zone, Generator::SampleID(_))
-                {
-                    let sample = &sf.samples[sample_id as usize];
-                    sample_name = Some(sample.name.clone());
-                    original_key = Some(sample.original_pitch);
-                    scale = sample.scale();
-                }
-                if sample_name.is_none() {
-                    continue;
-                }
-                let loop_mode = if let Some(Generator::SampleModes(loop_mode)) =
-                    get_zone_generator!(zone, Generator::SampleModes(_))
-                {
-                    Some(loop_mode)
-                } else {
-                    None
-                };
-                // TODO: check fine tune too
-                let mut root_note = None;
-                if let Some(Generator::OverridingRootKey(root)) =
-                    get_zone_generator!(zone, Generator::OverridingRootKey(_))
-                {
-                    root_note = Some(root);
-                } else if let Some(original_key) = original_key {
-                    root_note = Some(original_key as i16);
-                }
-                if root_note.is_none() {
-                    continue;
-                }
-                let mut root_note = root_note.unwrap();
-                // Scale the root note according to the adjusted sample rate
-                for _ in 0..scale.0 {
-                    root_note -= 12;
-                }
-                for _ in 0..scale.1 {
-                    root_note += 12;
-                }
-                if osc.is_empty() {
-                    osc.push((
-                        instrument_ix,
-                        zone_ix,
-                        low,
-                        high,
-                        sample_name,
-                        root_note,
-                        loop_mode,
-                    ));
-                } else {
-                    let (
-                        _prev_inst,
-                        _prev_zone,
-                        _prev_low,
-                        prev_high,
-                        prev_sample_name,
-                        prev_root_note,
-                        _prev_loop_mode,
-                    ) = osc.last_mut().unwrap();
-                    if sample_name != *prev_sample_name || root_note != *prev_root_note {
-                        // Adjust range so there are no gaps
-                        *prev_high = low;
-                        // Add the new sample
-                        osc.push((
-                            instrument_ix,
-                            zone_ix,
-                            low,
-                            high,
-                            sample_name,
-                            root_note,
-                            loop_mode,
-                        ));
+            EnvelopeAggregation::MiddleC => {
+                let distance = |r: &Region| {
+                    if r.key_range.1 < 60 {
+                        60 - r.key_range.1
                     } else {
-                        // Just extend previous range. In soundfonts, each range can have different params, but in deluge they can't.
-                        *prev_high = high;
+                        r.key_range.0.saturating_sub(60)
                     }
-                }
+                };
+                values
+                    .iter()
+                    .min_by_key(|(r, _)| distance(r))
+                    .map(|(_, v)| *v)
+                    .unwrap()
             }
+            EnvelopeAggregation::Maximum => values.iter().map(|(_, v)| *v).fold(f32::MIN, f32::max),
         }
-        info!("osc: {:?}", osc);
-        oscs.push(osc);
     }
 
-    // Write out the first two
-    let mut sound_builder = deluge::SoundBuilder::default();
-    sound_builder.firmware_version(Some("3.1.3".to_string()));
-    sound_builder.earliest_compatible_firmware(Some("3.1.0-beta".to_string()));
-    let mut default_params_builder = deluge::DefaultParamsBuilder::default();
-    let mut ix = 0;
-    let num = oscs.len();
-    if num > 2 {
-        warn!(
-            "{} has more osc than the deluge has, need to select",
-            preset.name
-        );
+    /// Levels are in dB, which is already a log scale
+    fn levels(self) -> Self {
+        match self {
+            EnvelopeAggregation::LogMean => EnvelopeAggregation::Mean,
+            x => x,
+        }
     }
-    for osc in &oscs[0..std::cmp::min(num, 2)] {
-        ix += 1;
-        let mut osc_builder = deluge::OscBuilder::default();
-        osc_builder
-            .osc_type(deluge::OscType::Sample)
-            .transpose(None)
-            .cents(None)
-            .retrig_phase(None)
-            .reversed(Some(0))
-            .time_stretch_enable(Some(0))
-            .time_stretch_amount(Some(0));
-        if let Some(loop_mode) = osc
-            .iter()
-            .find_map(|(_, _, _, _, _, _, loop_mode)| *loop_mode)
-        {
-            if loop_mode == LoopMode::ContinuousLoop || loop_mode == LoopMode::ReleaseLoop {
-                osc_builder.loop_mode(Some(2));
-            }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    pub extra_layers: ExtraLayers,
+    pub velocity_layer: VelocityLayer,
+    /// Put another velocity layer on osc2 and crossfade between them with velocity
+    pub velocity_crossfade: bool,
+    /// Scale the samples by the attenuation of their zones
+    pub bake_attenuation: bool,
+    pub envelope_aggregation: EnvelopeAggregation,
+    pub sample_rate: SampleRate,
+}
+
+fn patch_cable(
+    source: deluge::Source,
+    destination: deluge::Destination,
+    amount: deluge::Value,
+) -> deluge::PatchCable {
+    deluge::PatchCableBuilder::default()
+        .source(source)
+        .destination(destination)
+        .amount(amount)
+        .build()
+        .unwrap()
+}
+
+/// Keeps track of the samples written to the sample folder, so each is only written once and
+/// different samples don't overwrite each other
+pub struct SampleRegistry {
+    folder: PathBuf,
+    /// The SD card root that file names in the xml are relative to
+    root: Option<PathBuf>,
+    /// The written windows of each sample, by sample index
+    written: HashMap<usize, Vec<(SampleWindow, WavOptions, PathBuf)>>,
+    /// Lowercase file names in use, the deluge SD card is case insensitive
+    names: HashSet<String>,
+}
+
+impl SampleRegistry {
+    pub fn new(folder: &Path) -> SampleRegistry {
+        SampleRegistry {
+            folder: folder.to_owned(),
+            root: None,
+            written: HashMap::new(),
+            names: HashSet::new(),
         }
-        let single_sample = osc.len() == 1;
-        let mut sample_ranges = vec![];
-        for (ix, (i, o, _low, high, _sample_name, root, loop_mode)) in osc.iter().enumerate() {
-            let mut sample_range_builder = deluge::SampleRangeBuilder::default();
-            // The last sample must _not_ have range_top_note!
-            if ix != osc.len() - 1 {
-                sample_range_builder.range_top_note(Some(*high as i32));
-            }
-            if single_sample {
-                osc_builder.transpose(Some((60 - root).into()));
+    }
+
+    /// Writes the samples to SAMPLES/<font name> on an SD card with the root folder
+    pub fn in_sd_root(root: &Path, font_name: &str) -> SampleRegistry {
+        SampleRegistry {
+            root: Some(root.to_owned()),
+            ..SampleRegistry::new(&root.join("SAMPLES").join(SoundFont::safe_name(font_name)))
+        }
+    }
+
+    /// The fileName of a written sample, relative to the SD card root if there is one
+    fn file_name(&self, path: &Path) -> String {
+        let path = self
+            .root
+            .as_ref()
+            .and_then(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path);
+        let file_path: Vec<String> = path
+            .components()
+            .map(|x| x.as_os_str().to_str().unwrap().into())
+            .collect();
+        file_path.join("/")
+    }
+
+    /// Returns the path of the wav with the window of the sample, writing it the first time. The
+    /// file is given the name, with a number added if another file already has it.
+    fn export(
+        &mut self,
+        sf: &SoundFont,
+        sample_ix: usize,
+        window: &SampleWindow,
+        options: &WavOptions,
+        name: &str,
+    ) -> PathBuf {
+        let sample_ix = stereo_sample(sf, sample_ix);
+        let written = self.written.entry(sample_ix).or_default();
+        if let Some((_, _, path)) = written.iter().find(|(w, o, _)| w == window && o == options) {
+            return path.clone();
+        }
+        let path = self.folder.join(unique_name(&mut self.names, name));
+        match sf.stereo_pair(sample_ix) {
+            Some((left, right)) => sf.save_stereo_sample(
+                &sf.samples[left],
+                &sf.samples[right],
+                window,
+                options,
+                &path,
+            ),
+            None => sf.save_sample(&sf.samples[sample_ix], window, options, &path),
+        }
+        .unwrap_or_else(|e| panic!("could not save sample to {:?}, err: {}", path, e));
+        written.push((*window, *options, path.clone()));
+        path
+    }
+}
+
+/// Picks a wav file name not in the used names, adding a number to the name if needed
+fn unique_name(used: &mut HashSet<String>, name: &str) -> String {
+    let mut file_name = format!("{}.wav", name);
+    let mut n = 1;
+    while !used.insert(file_name.to_lowercase()) {
+        n += 1;
+        file_name = format!("{}_{}.wav", name, n);
+    }
+    file_name
+}
+
+/// The sample a region plays from, the left half if it is part of a stereo pair
+fn stereo_sample(sf: &SoundFont, sample: usize) -> usize {
+    sf.stereo_pair(sample).map_or(sample, |(left, _)| left)
+}
+
+/// A key range of an osc, played from one sample
+#[derive(Debug)]
+struct OscRange<'a> {
+    region: &'a Region,
+    high: u8,
+    sample_name: String,
+    transpose: i32,
+    cents: i32,
+    /// Initial attenuation in dB
+    attenuation: f32,
+    loop_mode: Option<LoopMode>,
+}
+
+/// Groups the regions of a preset into layers, one per preset zone
+fn preset_layers(sf: &SoundFont, ix: usize, options: &ConvertOptions) -> PresetLayers {
+    let preset = &sf.presets[ix];
+    // Each preset zone is a layer
+    let mut layers: Vec<Vec<Region>> = vec![];
+    for region in sf.regions(ix) {
+        match layers.last_mut() {
+            Some(layer) if layer[0].preset_zone == region.preset_zone => layer.push(region),
+            _ => layers.push(vec![region]),
+        }
+    }
+    // Zones that split on velocity can't be played by one osc, so pick one velocity layer
+    // per preset zone. If there is only one preset zone, osc2 is free for another one.
+    let mut crossfade = None;
+    let mut vel_layers = vec![];
+    for layer in &layers {
+        let velocity = options.velocity_layer.velocity(layer);
+        vel_layers.push(velocity_layer(layer, velocity));
+        if options.velocity_crossfade && layers.len() == 1 {
+            let other = if velocity >= 64 {
+                VelocityLayer::Softest
             } else {
-                sample_range_builder.transpose(Some((60 - root).into()));
+                VelocityLayer::Loudest
+            }
+            .velocity(layer);
+            let other_layer = velocity_layer(layer, other);
+            let same = |a: &[Region], b: &[Region]| {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b)
+                        .all(|(x, y)| x.instrument == y.instrument && x.sample == y.sample)
+            };
+            if !same(&other_layer, &vel_layers[0]) {
+                info!(
+                    "{}: crossfading velocity {} and {}",
+                    preset.name, velocity, other
+                );
+                vel_layers.push(other_layer);
+                crossfade = Some(velocity < other);
             }
-            if let Some(Generator::FineTune(cents)) =
-                get_zone_generator!(&instruments[*i][*o], Generator::FineTune(_))
-            {
-                if single_sample {
-                    osc_builder.cents(Some(cents.into()));
+        }
+    }
+    let mut layers = vel_layers;
+    for layer in &mut layers {
+        // Sort on key range
+        layer.sort_by_key(|r| r.key_range);
+        // The two halves of a stereo pair are written as one stereo file, so keep only one of the regions
+        let mut seen = vec![];
+        layer.retain(|r| match sf.stereo_pair(r.sample) {
+            Some(pair) => {
+                let key = (pair, r.key_range, r.vel_range);
+                if seen.contains(&key) {
+                    false
                 } else {
-                    sample_range_builder.cents(Some(cents.into()));
+                    seen.push(key);
+                    true
                 }
             }
-            if let Some(Generator::SampleID(sample_id)) =
-                get_zone_generator!(&instruments[*i][*o], Generator::SampleID(_))
-            {
-                let sample = &sf.samples[sample_id as usize];
-                let name = format!("{}.wav", SoundFont::safe_name(&sample.name));
-                let path = sample_folder.join(name);
-                let loop_mode = loop_mode.unwrap_or(LoopMode::NoLoop);
-                sf.save_sample(sample, loop_mode, &path)
-                    .unwrap_or_else(|e| panic!("could not save sample to {:?}, err: {}", path, e));
-                let file_path: Vec<String> = path
-                    .components()
-                    .map(|x| x.as_os_str().to_str().unwrap().into())
-                    .collect();
-                if single_sample {
-                    osc_builder.file_name(Some(file_path.join("/")));
-                } else {
-                    sample_range_builder.file_name(Some(file_path.join("/")));
+            None => true,
+        });
+        info!("layer: {:?}", layer);
+    }
+    PresetLayers { layers, crossfade }
+}
+
+struct PresetLayers {
+    layers: Vec<Vec<Region>>,
+    /// Set if osc1 and osc2 crossfade with velocity, true if osc1 has the softer layer
+    crossfade: Option<bool>,
+}
+
+/// The transpose and cents that play the region in tune at the key, where transpose 0 plays the
+/// sample at its root key when note 60 is played. Cents beyond ±50 carry into the transpose.
+fn tuning(sf: &SoundFont, region: &Region, key: u8) -> (i32, i32) {
+    let sample = &sf.samples[region.sample];
+    // 255 means unpitched, which should play at the original rate on middle C
+    let mut root_note = if sample.original_pitch <= 127 {
+        sample.original_pitch as i32
+    } else {
+        60
+    };
+    let mut cents = sample.correction as i32;
+    let mut scale_tuning = 100;
+    for g in &region.generators {
+        match *g {
+            Generator::ScaleTuning(x) => scale_tuning = x as i32,
+            Generator::OverridingRootKey(root) if root >= 0 => root_note = root as i32,
+            Generator::CoarseTune(x) => cents += 100 * x as i32,
+            Generator::FineTune(x) => cents += x as i32,
+            _ => {}
+        }
+    }
+    // The deluge moves 100 cents per key, so make up the difference to the scale tuning
+    cents += (key as i32 - root_note) * (scale_tuning - 100);
+    split_cents((60 - root_note) * 100 + cents)
+}
+
+/// Splits cents into semitones and the remaining cents, which are kept within ±50.
+fn split_cents(total: i32) -> (i32, i32) {
+    let transpose = (total as f32 / 100.0).round() as i32;
+    (transpose, total - 100 * transpose)
+}
+
+/// Merges the regions of a layer into key ranges. Neighbouring regions that play the same sample become one range.
+fn osc_ranges<'a>(
+    sf: &SoundFont,
+    regions: &'a [Region],
+    split_attenuation: bool,
+) -> Vec<OscRange<'a>> {
+    let mut osc: Vec<OscRange> = vec![];
+    for region in regions {
+        let zone = &region.generators;
+        let (low, high) = region.key_range;
+        let sample = &sf.samples[stereo_sample(sf, region.sample)];
+        let sample_name = sample.name.clone();
+        let loop_mode = if let Some(Generator::SampleModes(loop_mode)) =
+            get_zone_generator!(zone, Generator::SampleModes(_))
+        {
+            Some(loop_mode)
+        } else {
+            None
+        };
+        let attenuation = match get_zone_generator!(zone, Generator::InitialAttenuation(_))
+            .and_then(|g| g.value())
+        {
+            Some(Unit::Level(db)) => db,
+            _ => 0.0,
+        };
+        let scale_tuning = match get_zone_generator!(zone, Generator::ScaleTuning(_)) {
+            Some(Generator::ScaleTuning(x)) => x,
+            _ => 100,
+        };
+        // Deluge ranges always track the keyboard, so keys that don't need a range each
+        let spans: Vec<(u8, u8)> = if scale_tuning == 100 {
+            vec![(low, high)]
+        } else {
+            (low..=high).map(|key| (key, key)).collect()
+        };
+        for (low, high) in spans {
+            let (transpose, cents) = tuning(sf, region, low);
+            // A range starts after the one before it
+            let prev_low = match osc.len() {
+                0 | 1 => 0,
+                n => osc[n - 2].high as u16 + 1,
+            };
+            match osc.last_mut() {
+                Some(prev)
+                    if sample_name == prev.sample_name
+                        && (transpose, cents) == (prev.transpose, prev.cents)
+                        && (!split_attenuation || attenuation == prev.attenuation) =>
+                {
+                    // Just extend previous range. In soundfonts, each range can have different params, but in deluge they can't.
+                    prev.high = prev.high.max(high);
+                    prev.attenuation = prev.attenuation.min(attenuation);
+                    continue;
                 }
-                // TODO: take generator sample offsets into account
-                let mut zone_builder = deluge::ZoneBuilder::default();
-                zone_builder.end_sample_pos(sample.end - sample.start);
-                if loop_mode != LoopMode::NoLoop {
-                    zone_builder.start_loop_pos(Some(sample.start_loop - sample.start));
-                    zone_builder.end_loop_pos(Some(sample.end_loop - sample.start));
+                // Overlapping the whole start of the previous range, which keeps its keys
+                Some(prev) if low as u16 <= prev_low && high <= prev.high => {
+                    info!(
+                        "keys {}-{} of {} are already covered, skipping",
+                        low, high, sample_name
+                    );
+                    continue;
                 }
-                if single_sample {
-                    osc_builder.zone(Some(zone_builder.build().unwrap()));
-                } else {
-                    sample_range_builder.zone(zone_builder.build().unwrap());
+                Some(_) if low as u16 <= prev_low => {}
+                Some(prev) => {
+                    // Adjust range so there are no gaps
+                    prev.high = low - 1;
                 }
+                None => {}
             }
-            if !single_sample {
-                let sample_range = sample_range_builder.build().unwrap();
-                sample_ranges.push(sample_range);
-            }
+            // Add the new sample
+            osc.push(OscRange {
+                region,
+                high,
+                sample_name: sample_name.clone(),
+                transpose,
+                cents,
+                attenuation,
+                loop_mode,
+            });
+        }
+    }
+    info!("osc: {:?}", osc);
+    osc
+}
+
+/// How far the ranges can be turned up in dB, with their attenuation above `common` scaled into
+/// them, before the loudest peak reaches full scale
+fn headroom(sf: &SoundFont, oscs: &[Vec<OscRange>], common: f32) -> f32 {
+    let peak = oscs
+        .iter()
+        .flatten()
+        .map(|range| {
+            let region = range.region;
+            let ix = stereo_sample(sf, region.sample);
+            let window = region
+                .sample_window(&sf.samples[region.sample])
+                .rebase(&sf.samples[region.sample], &sf.samples[ix]);
+            sf.peak_level(ix, &window) * 10.0f32.powf(-(range.attenuation - common) / 20.0)
+        })
+        .fold(0.0, f32::max);
+    if peak > 0.0 {
+        -20.0 * peak.log10()
+    } else {
+        0.0
+    }
+}
+
+/// Creates a sample osc playing the ranges, and saves the samples. With `baked_attenuation`, each
+/// range's attenuation above it is scaled into the samples, which [headroom] can make louder than
+/// the original without clipping.
+fn sample_osc(
+    sf: &SoundFont,
+    samples: &mut SampleRegistry,
+    osc: &[OscRange],
+    baked_attenuation: Option<f32>,
+    sample_rate: SampleRate,
+) -> deluge::Osc {
+    let mut osc_builder = deluge::OscBuilder::default();
+    osc_builder
+        .osc_type(deluge::OscType::Sample)
+        .transpose(None)
+        .cents(None)
+        .retrig_phase(None)
+        .reversed(Some(0))
+        .time_stretch_enable(Some(0))
+        .time_stretch_amount(Some(0));
+    if let Some(loop_mode) = osc.iter().find_map(|r| r.loop_mode) {
+        if loop_mode == LoopMode::ContinuousLoop || loop_mode == LoopMode::ReleaseLoop {
+            osc_builder.loop_mode(Some(2));
+        }
+    }
+    let single_sample = osc.len() == 1;
+    let mut sample_ranges = vec![];
+    for (ix, range) in osc.iter().enumerate() {
+        let mut sample_range_builder = deluge::SampleRangeBuilder::default();
+        // The last sample must _not_ have range_top_note!
+        if ix != osc.len() - 1 {
+            sample_range_builder.range_top_note(Some(range.high as i32));
+        }
+        if single_sample {
+            osc_builder
+                .transpose(Some(range.transpose))
+                .cents(Some(range.cents));
+        } else {
+            sample_range_builder
+                .transpose(Some(range.transpose))
+                .cents(Some(range.cents));
+        }
+        let region = range.region;
+        let sample = &sf.samples[stereo_sample(sf, region.sample)];
+        let window = region
+            .sample_window(&sf.samples[region.sample])
+            .rebase(&sf.samples[region.sample], sample);
+        // The file has the whole sample, unless the offsets reach outside of it
+        let file_window = SampleWindow {
+            start: window.start.min(sample.start),
+            end: window.end.max(sample.end),
+            ..window
+        };
+        let mut name = SoundFont::safe_name(&sample.name);
+        if file_window.start != sample.start || file_window.end != sample.end {
+            name.push_str(&format!("_{}_{}", file_window.start, file_window.end));
+        }
+        let attenuation = baked_attenuation.map_or(0.0, |common| range.attenuation - common);
+        if attenuation != 0.0 {
+            name.push_str(&format!("_{}cB", (attenuation * 10.0).round()));
+        }
+        let loop_mode = range.loop_mode.unwrap_or(LoopMode::NoLoop);
+        let wav_options = WavOptions {
+            loop_mode,
+            gain: 10.0f32.powf(-attenuation / 20.0),
+            sample_rate: sample_rate.output_rate(sample.sample_rate),
+        };
+        let path = samples.export(sf, region.sample, &file_window, &wav_options, &name);
+        let file_name = samples.file_name(&path);
+        if single_sample {
+            osc_builder.file_name(Some(file_name));
+        } else {
+            sample_range_builder.file_name(Some(file_name));
+        }
+        // Positions in the written file, which may have another rate
+        let file_pos = |pos: u32| {
+            resample::position(
+                pos - file_window.start,
+                sample.sample_rate,
+                wav_options.sample_rate,
+            )
+        };
+        let mut zone_builder = deluge::ZoneBuilder::default();
+        zone_builder
+            .start_sample_pos(file_pos(window.start))
+            .end_sample_pos(file_pos(window.end));
+        if loop_mode != LoopMode::NoLoop {
+            zone_builder.start_loop_pos(Some(file_pos(window.start_loop)));
+            zone_builder.end_loop_pos(Some(file_pos(window.end_loop)));
+        }
+        if single_sample {
+            osc_builder.zone(Some(zone_builder.build().unwrap()));
+        } else {
+            sample_range_builder.zone(zone_builder.build().unwrap());
         }
         if !single_sample {
-            osc_builder.sample_ranges(Some(
-                deluge::SampleRangesBuilder::default()
-                    .sample_range(sample_ranges)
-                    .build()
-                    .unwrap(),
-            ));
+            let sample_range = sample_range_builder.build().unwrap();
+            sample_ranges.push(sample_range);
+        }
+    }
+    if !single_sample {
+        osc_builder.sample_ranges(Some(
+            deluge::SampleRangesBuilder::default()
+                .sample_range(sample_ranges)
+                .build()
+                .unwrap(),
+        ));
+    }
+    osc_builder.build().unwrap()
+}
+
+/// The stages of a SoundFont volume envelope, times in seconds and the sustain in dB
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct EnvelopeStages {
+    delay: f32,
+    attack: f32,
+    hold: f32,
+    decay: f32,
+    sustain: f32,
+}
+
+impl EnvelopeStages {
+    /// The level 0-1 at `t` seconds after note on. The attack is linear, and the decay is linear in dB.
+    fn level(&self, t: f32) -> f32 {
+        let t = t - self.delay;
+        if t < 0.0 {
+            return 0.0;
         }
-        let osc = osc_builder.build().unwrap();
-        if ix == 1 {
-            sound_builder.osc1(osc);
-            default_params_builder.osc1_volume(deluge::Value(0x7FFFFFFF));
+        if t < self.attack {
+            return t / self.attack;
+        }
+        let t = t - self.attack - self.hold;
+        if t < 0.0 {
+            return 1.0;
+        }
+        let db = if t < self.decay {
+            self.sustain * t / self.decay
         } else {
-            sound_builder.osc2(osc);
-            default_params_builder.osc2_volume(deluge::Value(0x7FFFFFFF));
+            self.sustain
+        };
+        10.0f32.powf(-db / 20.0)
+    }
+
+    /// The largest difference in level to another envelope, with the time it happens at
+    fn deviation(&self, other: impl Fn(f32) -> f32) -> (f32, f32) {
+        let end = (self.delay + self.attack + self.hold + self.decay) * 1.1;
+        // Differences that last less than 10 ms are hard to hear, so don't look closer than that
+        let step = (end / 1000.0).max(0.01);
+        (0..=(end / step) as usize)
+            .map(|ix| {
+                let t = step * ix as f32;
+                ((self.level(t) - other(t)).abs(), t)
+            })
+            .fold((0.0, 0.0), |a, b| if b.0 > a.0 { b } else { a })
+    }
+}
+
+/// Combines the volume envelopes of the regions into one deluge envelope. The deluge has no delay
+/// or hold, so delay is folded into the attack and hold into the decay. If the hold is longer than
+/// the decay and envelope2 is free, envelope2 is returned with a patch cable to volume that keeps
+/// the level up during the hold instead.
+fn amp_envelope(
+    regions: &[&Region],
+    aggregation: EnvelopeAggregation,
+    env2_free: bool,
+) -> (
+    deluge::Envelope,
+    Option<(deluge::Envelope, deluge::PatchCable)>,
+) {
+    let mut delay_time = vec![];
+    let mut attack_time = vec![];
+    let mut hold_time = vec![];
+    let mut decay_time = vec![];
+    let mut sustain_vol = vec![];
+    let mut release_time = vec![];
+    for region in regions {
+        for g in &region.generators {
+            match (g, g.value()) {
+                (Generator::DelayVolEnv(_), Some(Unit::Seconds(s))) => {
+                    delay_time.push((*region, s))
+                }
+                (Generator::AttackVolEnv(_), Some(Unit::Seconds(s))) => {
+                    attack_time.push((*region, s))
+                }
+                (Generator::HoldVolEnv(_), Some(Unit::Seconds(s))) => hold_time.push((*region, s)),
+                (Generator::DecayVolEnv(_), Some(Unit::Seconds(s))) => {
+                    decay_time.push((*region, s))
+                }
+                (Generator::SustainVolEnv(_), Some(Unit::Level(lvl))) => {
+                    sustain_vol.push((*region, lvl))
+                }
+                (Generator::ReleaseVolEnv(_), Some(Unit::Seconds(s))) => {
+                    release_time.push((*region, s))
+                }
+                _ => {}
+            }
         }
     }
-    println!("==== {} ====", preset.name);
-    let attack_duration = std::time::Duration::from_secs_f32(if !attack_time.is_empty() {
-        attack_time.iter().sum::<f32>() / attack_time.len() as f32
-    } else {
-        0.0
-    });
+    let stages = EnvelopeStages {
+        delay: aggregation.aggregate(&delay_time),
+        attack: aggregation.aggregate(&attack_time),
+        hold: aggregation.aggregate(&hold_time),
+        decay: aggregation.aggregate(&decay_time),
+        sustain: aggregation.levels().aggregate(&sustain_vol),
+    };
+    info!(
+        "delay: {:?}, attack: {:?}, hold: {:?}, decay: {:?}",
+        values(&delay_time),
+        values(&attack_time),
+        values(&hold_time),
+        values(&decay_time)
+    );
+    let attack_duration = std::time::Duration::from_secs_f32(stages.delay + stages.attack);
     println!(
-        "attack: {:?}, {}, {:?}",
+        "attack: {:?}, {}",
         attack_duration,
         deluge::attack_to_value(attack_duration).to_deluge_val(),
-        attack_time
     );
-    let decay_duration = std::time::Duration::from_secs_f32(if !decay_time.is_empty() {
-        decay_time.iter().sum::<f32>() / decay_time.len() as f32
+    let shape_hold = env2_free && stages.hold > stages.decay && stages.sustain > 0.0;
+    let decay_duration = std::time::Duration::from_secs_f32(if shape_hold {
+        stages.decay
     } else {
-        0.0
+        stages.hold + stages.decay
     });
     println!(
-        "decay: {:?}, {}, {:?}",
+        "decay: {:?}, {}",
         decay_duration,
         deluge::decay_to_value(decay_duration).to_deluge_val(),
-        decay_time
     );
-    let sustain_level = if !sustain_vol.is_empty() {
-        sustain_vol.iter().sum::<f32>() / sustain_vol.len() as f32
-    } else {
-        0.0
-    };
+    let sustain_level = stages.sustain;
     println!(
         "sustain: {} dB, {}, {:?}",
         sustain_level,
         deluge::sustain_to_value(-sustain_level).to_deluge_val(),
-        sustain_vol
+        values(&sustain_vol)
     );
-    let release_duration = std::time::Duration::from_secs_f32(if !release_time.is_empty() {
-        release_time.iter().sum::<f32>() / release_time.len() as f32
-    } else {
-        0.0
-    });
+    let release_duration = std::time::Duration::from_secs_f32(aggregation.aggregate(&release_time));
     println!(
         "release: {:?}, {}, {:?}",
         release_duration,
         deluge::release_to_value(release_duration).to_deluge_val(),
-        release_time
+        values(&release_time)
+    );
+    let envelope = deluge::EnvelopeBuilder::default()
+        .attack(deluge::attack_to_value(attack_duration))
+        .decay(deluge::decay_to_value(decay_duration))
+        .sustain(deluge::sustain_to_value(-sustain_level))
+        .release(deluge::release_to_value(release_duration))
+        .build()
+        .unwrap();
+    let approximation = EnvelopeStages {
+        delay: 0.0,
+        attack: attack_duration.as_secs_f32(),
+        hold: 0.0,
+        decay: decay_duration.as_secs_f32(),
+        sustain: sustain_level,
+    };
+    if !shape_hold {
+        let (deviation, at) = stages.deviation(|t| approximation.level(t));
+        report_deviation("envelope", deviation, at);
+        return (envelope, None);
+    }
+    // Envelope2 falls from full to nothing over the hold, making up for envelope1 decaying early
+    let hold_envelope = deluge::EnvelopeBuilder::default()
+        .attack(deluge::attack_to_value(attack_duration))
+        .decay(deluge::decay_to_value(std::time::Duration::from_secs_f32(
+            stages.hold,
+        )))
+        .sustain(deluge::Value::from_deluge_val(0))
+        .release(deluge::release_to_value(release_duration))
+        .build()
+        .unwrap();
+    let lost = 1.0 - 10.0f32.powf(-sustain_level / 20.0);
+    let hold_level = |t: f32| {
+        let t = t - approximation.attack;
+        if t < 0.0 {
+            t / approximation.attack + 1.0
+        } else {
+            (1.0 - t / stages.hold).max(0.0)
+        }
+    };
+    // The cable brings the level up towards full by as much as the sustain takes it down
+    let (deviation, at) = stages.deviation(|t| {
+        let level = approximation.level(t);
+        level + (1.0 - level) * lost * hold_level(t)
+    });
+    report_deviation("envelope with hold on envelope2", deviation, at);
+    let cable = patch_cable(
+        deluge::Source::Envelope2,
+        deluge::Destination::Volume,
+        deluge::volume_depth_to_patch_amount(sustain_level),
+    );
+    (envelope, Some((hold_envelope, cable)))
+}
+
+/// Deviations of the converted envelope above this are warned about
+const ENVELOPE_DEVIATION_WARNING: f32 = 0.1;
+
+fn report_deviation(what: &str, deviation: f32, at: f32) {
+    let level = if deviation > ENVELOPE_DEVIATION_WARNING {
+        Level::Warn
+    } else {
+        Level::Info
+    };
+    log!(
+        level,
+        "{} deviates by up to {:.0}% at {:?}",
+        what,
+        deviation * 100.0,
+        std::time::Duration::from_secs_f32(at)
+    );
+}
+
+fn values(values: &[(&Region, f32)]) -> Vec<f32> {
+    values.iter().map(|(_, v)| *v).collect()
+}
+
+/// Averages the filter cutoff and resonance of the regions, None if the filter is open
+fn lpf_params(regions: &[&Region]) -> Option<(deluge::Value, deluge::Value)> {
+    let mut cutoff = vec![];
+    let mut resonance = vec![];
+    for region in regions {
+        let zone = &region.generators;
+        // Average the cutoff in cents, i.e. on a log scale
+        if let Some(Generator::InitialFilterFc(fc)) =
+            get_zone_generator!(zone, Generator::InitialFilterFc(_))
+        {
+            cutoff.push(fc as f32);
+        }
+        if let Some(g) = get_zone_generator!(zone, Generator::InitialFilterQ(_)) {
+            if let Some(Unit::Level(db)) = g.value() {
+                resonance.push(db);
+            }
+        }
+    }
+    if cutoff.is_empty() {
+        return None;
+    }
+    let cents = mean(&cutoff);
+    // The default 13500 cents (about 20 kHz) means no filtering
+    if cents >= 13500.0 {
+        return None;
+    }
+    let frequency = 8.176 * 2.0f32.powf(cents / 1200.0);
+    let q = mean(&resonance);
+    info!(
+        "lpf: {} Hz, {}, resonance: {} dB, {}",
+        frequency,
+        deluge::lpf_frequency_to_value(frequency).to_deluge_val(),
+        q,
+        deluge::lpf_resonance_to_value(q).to_deluge_val()
     );
-    // Set the amp envelope to have attack 50, decay 25, sustain 50, release 25
-    default_params_builder.envelope1(
-        deluge::EnvelopeBuilder::default()
-            .attack(deluge::attack_to_value(attack_duration))
-            .decay(deluge::decay_to_value(decay_duration))
-            .sustain(deluge::sustain_to_value(-sustain_level))
-            .release(deluge::release_to_value(release_duration))
+    Some((
+        deluge::lpf_frequency_to_value(frequency),
+        deluge::lpf_resonance_to_value(q),
+    ))
+}
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+}
+
+/// Converts the modulation envelope to envelope2, with patch cables to the filter and pitch.
+/// The envelope is combined like the volume envelope, the depths are averaged.
+/// None if the modulation envelope isn't routed anywhere.
+fn mod_envelope(
+    regions: &[&Region],
+    aggregation: EnvelopeAggregation,
+) -> Option<(deluge::Envelope, Vec<deluge::PatchCable>)> {
+    let mut attack_time = vec![];
+    let mut decay_time = vec![];
+    let mut sustain_level = vec![];
+    let mut release_time = vec![];
+    let mut to_pitch = vec![];
+    let mut to_filter = vec![];
+    for region in regions {
+        for g in &region.generators {
+            match (g, g.value()) {
+                (Generator::AttackModEnv(_), Some(Unit::Seconds(s))) => {
+                    attack_time.push((*region, s))
+                }
+                (Generator::DecayModEnv(_), Some(Unit::Seconds(s))) => {
+                    decay_time.push((*region, s))
+                }
+                (Generator::ReleaseModEnv(_), Some(Unit::Seconds(s))) => {
+                    release_time.push((*region, s))
+                }
+                // The sustain is the decrease in 0.1% units
+                (Generator::SustainModEnv(x), _) => {
+                    sustain_level.push((*region, 1.0 - (*x).clamp(0, 1000) as f32 / 1000.0))
+                }
+                (Generator::ModEnvToPitch(x), _) => to_pitch.push(*x as f32),
+                (Generator::ModEnvToFilterFc(x), _) => to_filter.push(*x as f32),
+                _ => {}
+            }
+        }
+    }
+    let to_pitch = mean(&to_pitch);
+    let to_filter = mean(&to_filter);
+    if to_pitch == 0.0 && to_filter == 0.0 {
+        return None;
+    }
+    let attack = std::time::Duration::from_secs_f32(aggregation.aggregate(&attack_time));
+    let decay = std::time::Duration::from_secs_f32(aggregation.aggregate(&decay_time));
+    let sustain = aggregation.levels().aggregate(&sustain_level);
+    let release = std::time::Duration::from_secs_f32(aggregation.aggregate(&release_time));
+    info!(
+        "mod env: attack {:?}, decay {:?}, sustain {}, release {:?}, pitch {} cents, filter {} cents",
+        attack, decay, sustain, release, to_pitch, to_filter
+    );
+    let envelope = deluge::EnvelopeBuilder::default()
+        .attack(deluge::attack_to_value(attack))
+        .decay(deluge::decay_to_value(decay))
+        .sustain(deluge::Value::from_deluge_val(
+            (sustain * 50.0).round() as i32
+        ))
+        .release(deluge::release_to_value(release))
+        .build()
+        .unwrap();
+    let mut patch_cables = vec![];
+    if to_filter != 0.0 {
+        patch_cables.push(patch_cable(
+            deluge::Source::Envelope2,
+            deluge::Destination::LpfFrequency,
+            deluge::lpf_cents_to_patch_amount(to_filter),
+        ));
+    }
+    if to_pitch != 0.0 {
+        patch_cables.push(patch_cable(
+            deluge::Source::Envelope2,
+            deluge::Destination::Pitch,
+            deluge::pitch_depth_to_patch_amount(to_pitch),
+        ));
+    }
+    Some((envelope, patch_cables))
+}
+
+/// Sets envelope1 from the volume envelope, and envelope2 from the modulation envelope or to
+/// shape a long hold of the volume envelope
+fn add_envelopes(
+    regions: &[&Region],
+    aggregation: EnvelopeAggregation,
+    params: &mut deluge::DefaultParamsBuilder,
+    patch_cables: &mut Vec<deluge::PatchCable>,
+) {
+    let mod_env = mod_envelope(regions, aggregation);
+    let (envelope, hold) = amp_envelope(regions, aggregation, mod_env.is_none());
+    params.envelope1(envelope);
+    if let Some((envelope, cables)) = mod_env {
+        params.envelope2(envelope);
+        patch_cables.extend(cables);
+    } else if let Some((envelope, cable)) = hold {
+        params.envelope2(envelope);
+        patch_cables.push(cable);
+    }
+}
+
+/// The vibrato and modulation LFO rates in Hz, averaged in cents, i.e. on a log scale
+fn lfo_rates(regions: &[&Region]) -> (f32, f32) {
+    let mut vib_freq = vec![];
+    let mut mod_freq = vec![];
+    for region in regions {
+        for g in &region.generators {
+            match g {
+                Generator::FreqVibLFO(x) => vib_freq.push(*x as f32),
+                Generator::FreqModLFO(x) => mod_freq.push(*x as f32),
+                _ => {}
+            }
+        }
+    }
+    let rate = |freq: &[f32]| 8.176 * 2.0f32.powf(mean(freq) / 1200.0);
+    (rate(&vib_freq), rate(&mod_freq))
+}
+
+/// Sets the LFO rates from the vibrato (lfo1) and modulation (lfo2) LFOs, and patches them to
+/// pitch, filter and volume. The LFO delays have no deluge equivalent.
+fn add_lfos(
+    regions: &[&Region],
+    params: &mut deluge::DefaultParamsBuilder,
+    patch_cables: &mut Vec<deluge::PatchCable>,
+) {
+    let mut vib_to_pitch = vec![];
+    let mut mod_to_pitch = vec![];
+    let mut mod_to_filter = vec![];
+    let mut mod_to_volume = vec![];
+    for region in regions {
+        for g in &region.generators {
+            match (g, g.value()) {
+                (Generator::VibLfoToPitch(x), _) => vib_to_pitch.push(*x as f32),
+                (Generator::ModLfoToPitch(x), _) => mod_to_pitch.push(*x as f32),
+                (Generator::ModLfoToFilterFc(x), _) => mod_to_filter.push(*x as f32),
+                // Centibels
+                (Generator::ModLfoToVolume(x), _) => mod_to_volume.push(*x as f32 / 10.0),
+                _ => {}
+            }
+        }
+    }
+    let (vib_rate, mod_rate) = lfo_rates(regions);
+    let vib_to_pitch = mean(&vib_to_pitch);
+    if vib_to_pitch != 0.0 {
+        info!("vibrato lfo: {} Hz, pitch {} cents", vib_rate, vib_to_pitch);
+        params.lfo1_rate(deluge::lfo_rate_to_value(vib_rate));
+        patch_cables.push(patch_cable(
+            deluge::Source::Lfo1,
+            deluge::Destination::Pitch,
+            deluge::pitch_depth_to_patch_amount(vib_to_pitch),
+        ));
+    }
+    let mod_to_pitch = mean(&mod_to_pitch);
+    let mod_to_filter = mean(&mod_to_filter);
+    let mod_to_volume = mean(&mod_to_volume);
+    if mod_to_pitch != 0.0 || mod_to_filter != 0.0 || mod_to_volume != 0.0 {
+        info!(
+            "mod lfo: {} Hz, pitch {} cents, filter {} cents, volume {} dB",
+            mod_rate, mod_to_pitch, mod_to_filter, mod_to_volume
+        );
+        params.lfo2_rate(deluge::lfo_rate_to_value(mod_rate));
+        if mod_to_pitch != 0.0 {
+            patch_cables.push(patch_cable(
+                deluge::Source::Lfo2,
+                deluge::Destination::Pitch,
+                deluge::pitch_depth_to_patch_amount(mod_to_pitch),
+            ));
+        }
+        if mod_to_filter != 0.0 {
+            patch_cables.push(patch_cable(
+                deluge::Source::Lfo2,
+                deluge::Destination::LpfFrequency,
+                deluge::lpf_cents_to_patch_amount(mod_to_filter),
+            ));
+        }
+        if mod_to_volume != 0.0 {
+            patch_cables.push(patch_cable(
+                deluge::Source::Lfo2,
+                deluge::Destination::Volume,
+                deluge::volume_depth_to_patch_amount(mod_to_volume),
+            ));
+        }
+    }
+}
+
+/// Sets the pan and reverb amount from the regions, and the mod FX depth from the chorus send.
+/// Returns true if there is chorus. Stereo samples are panned by their channels, so they are left
+/// out of the pan.
+fn add_pan_and_sends(
+    sf: &SoundFont,
+    regions: &[&Region],
+    params: &mut deluge::DefaultParamsBuilder,
+) -> bool {
+    let mut pan = vec![];
+    let mut reverb = vec![];
+    let mut chorus = vec![];
+    for region in regions {
+        for g in &region.generators {
+            match g.value() {
+                Some(Unit::Pan(x)) if sf.stereo_pair(region.sample).is_none() => pan.push(x),
+                Some(Unit::Send(x)) => match g {
+                    Generator::ReverbEffectsSend(_) => reverb.push(x),
+                    Generator::ChorusEffectsSend(_) => chorus.push(x),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+    let (pan, reverb, chorus) = (mean(&pan), mean(&reverb), mean(&chorus));
+    info!("pan: {}%, reverb: {}%, chorus: {}%", pan, reverb, chorus);
+    if pan != 0.0 {
+        params.pan(deluge::pan_to_value(pan));
+    }
+    if reverb > 0.0 {
+        params.reverb_amount(deluge::send_to_value(reverb));
+    }
+    if chorus > 0.0 {
+        // The rate the deluge starts the chorus at
+        params
+            .mod_fx_rate(deluge::Value(0xE0000000))
+            .mod_fx_depth(deluge::send_to_value(chorus));
+    }
+    chorus > 0.0
+}
+
+/// Translates the modulators of the regions to patch cables, and returns MIDI knobs for the mod
+/// wheel. Default modulators that have no deluge equivalent are left out, any other modulators
+/// that can't be mapped are reported.
+fn add_modulators(
+    regions: &[&Region],
+    params: &mut deluge::DefaultParamsBuilder,
+    patch_cables: &mut Vec<deluge::PatchCable>,
+) -> Vec<deluge::MidiKnob> {
+    // Average the amounts of identical modulators
+    let mut modulators: Vec<(&ModList, Vec<f32>)> = vec![];
+    for region in regions {
+        for m in &region.modulators {
+            match modulators.iter_mut().find(|(x, _)| x.is_identical(m)) {
+                Some((_, amounts)) => amounts.push(m.amount as f32),
+                None => modulators.push((m, vec![m.amount as f32])),
+            }
+        }
+    }
+    let defaults = ModList::defaults();
+    let (vib_rate, mod_rate) = lfo_rates(regions);
+    let mut midi_knobs = vec![];
+    for (m, amounts) in modulators {
+        let amount = mean(&amounts);
+        if amount == 0.0 {
+            continue;
+        }
+        // The change over the whole range of the source, the shape of the curve is lost except
+        // for volume
+        let mut change = match m.src_oper.polarity {
+            SourcePolarity::Unipolar => amount,
+            SourcePolarity::Bipolar => 2.0 * amount,
+        };
+        if m.src_oper.direction == SourceDirection::Reverse {
+            change = -change;
+        }
+        let source = match m.src_oper.index {
+            SourceEnumeratorController::NoteOnVelocity => Some(deluge::Source::Velocity),
+            SourceEnumeratorController::ChannelPressure => Some(deluge::Source::Aftertouch),
+            _ => None,
+        };
+        let lfo = match m.dest_oper {
+            DestOper::Generator(Generator::VibLfoToPitch(_)) => Some(deluge::Source::Lfo1),
+            DestOper::Generator(Generator::ModLfoToPitch(_)) => Some(deluge::Source::Lfo2),
+            _ => None,
+        };
+        let direct = m.amt_src_oper.index == SourceEnumeratorController::NoController
+            && m.trans_oper == ModularTransform::Linear;
+        // The LFO may only be used by the modulator, so it needs a rate
+        let mut set_lfo_rate = |lfo: &deluge::Source| {
+            if *lfo == deluge::Source::Lfo1 {
+                params.lfo1_rate(deluge::lfo_rate_to_value(vib_rate));
+            } else {
+                params.lfo2_rate(deluge::lfo_rate_to_value(mod_rate));
+            }
+        };
+        let mapped = match (source, lfo, m.dest_oper) {
+            _ if !direct => false,
+            (Some(source), _, DestOper::Generator(Generator::InitialAttenuation(_))) => {
+                patch_cables.push(patch_cable(
+                    source,
+                    deluge::Destination::Volume,
+                    deluge::volume_depth_to_patch_amount(volume_cable_depth(m, amount)),
+                ));
+                true
+            }
+            (Some(source), _, DestOper::Generator(Generator::InitialFilterFc(_))) => {
+                patch_cables.push(patch_cable(
+                    source,
+                    deluge::Destination::LpfFrequency,
+                    deluge::lpf_cents_to_patch_amount(change),
+                ));
+                true
+            }
+            (Some(source), Some(lfo), _) => {
+                set_lfo_rate(&lfo);
+                lfo_pitch_cable(patch_cables, lfo)
+                    .add_depth_control(source, deluge::pitch_depth_to_patch_amount(change));
+                true
+            }
+            (None, Some(lfo), _) if m.src_oper.index == SourceEnumeratorController::MidiCC(1) => {
+                // The mod wheel sets the depth of the LFO to pitch cable
+                set_lfo_rate(&lfo);
+                lfo_pitch_cable(patch_cables, lfo.clone());
+                let knob = deluge::MidiKnobBuilder::default()
+                    .cc_number(1)
+                    .controls_param(deluge::Destination::Pitch)
+                    .patch_amount_from_source(Some(lfo))
+                    .build()
+                    .unwrap();
+                if !midi_knobs.contains(&knob) {
+                    midi_knobs.push(knob);
+                }
+                true
+            }
+            _ => false,
+        };
+        let is_default = defaults
+            .iter()
+            .any(|d| d.is_identical(m) && d.amount as f32 == amount);
+        if !mapped && !is_default {
+            warn!(
+                "modulator not mapped: {:?} to {:?}, amount {}",
+                m.src_oper.index, m.dest_oper, amount
+            );
+        }
+    }
+    midi_knobs
+}
+
+/// The output of a modulator source for an input of 0-1, following its curve, direction and
+/// polarity
+fn source_value(source: &Modulator, x: f32) -> f32 {
+    let x = match source.direction {
+        SourceDirection::Forward => x,
+        SourceDirection::Reverse => 1.0 - x,
+    };
+    // The concave curve is 96 dB of attenuation on the square of the input, convex is its mirror
+    let concave = |x: f32| (-40.0 / 96.0 * (1.0 - x).max(1e-10).log10()).min(1.0);
+    let y = match source.continuity {
+        SourceType::Concave => concave(x),
+        SourceType::Convex => 1.0 - concave(1.0 - x),
+        SourceType::Switch => (x >= 0.5) as i32 as f32,
+        _ => x,
+    };
+    match source.polarity {
+        SourcePolarity::Unipolar => y,
+        SourcePolarity::Bipolar => 2.0 * y - 1.0,
+    }
+}
+
+/// The depth in dB of a cable to volume that best matches the attenuation curve of a velocity or
+/// aftertouch modulator. The cable changes the level linearly, so it is fitted to the curve in dB
+/// over all velocities. Negative if the level goes down with the source.
+fn volume_cable_depth(m: &ModList, amount: f32) -> f32 {
+    let db: Vec<f32> = (1..=127)
+        .map(|v| -amount * source_value(&m.src_oper, v as f32 / 127.0) / 10.0)
+        .collect();
+    let max = db.iter().copied().fold(f32::MIN, f32::max);
+    let rising = db[126] >= db[0];
+    let error = |ratio: f32| {
+        db.iter()
+            .enumerate()
+            .map(|(ix, d)| {
+                let x = (ix + 1) as f32 / 127.0;
+                let level = 1.0 - ratio * if rising { 1.0 - x } else { x };
+                let e = 20.0 * level.max(1e-10).log10() - (d - max);
+                e * e
+            })
+            .sum::<f32>()
+    };
+    let ratio = (0..=1000)
+        .map(|ix| ix as f32 / 1000.0)
+        .min_by(|a, b| error(*a).total_cmp(&error(*b)))
+        .unwrap();
+    let depth = -20.0 * (1.0 - ratio).log10();
+    if rising {
+        depth
+    } else {
+        -depth
+    }
+}
+
+/// The patch cable from the LFO to pitch, which is added with no amount if there isn't one
+fn lfo_pitch_cable(
+    patch_cables: &mut Vec<deluge::PatchCable>,
+    lfo: deluge::Source,
+) -> &mut deluge::PatchCable {
+    let ix = match patch_cables
+        .iter()
+        .position(|c| *c.source() == lfo && *c.destination() == deluge::Destination::Pitch)
+    {
+        Some(ix) => ix,
+        None => {
+            patch_cables.push(patch_cable(
+                lfo,
+                deluge::Destination::Pitch,
+                deluge::Value::from_patch_amount(0.0),
+            ));
+            patch_cables.len() - 1
+        }
+    };
+    &mut patch_cables[ix]
+}
+
+/// MIDI knobs for the sound, None if there are none
+fn midi_knobs(knobs: Vec<deluge::MidiKnob>) -> Option<deluge::MidiKnobs> {
+    if knobs.is_empty() {
+        None
+    } else {
+        Some(
+            deluge::MidiKnobsBuilder::default()
+                .midi_knob(knobs)
+                .build()
+                .unwrap(),
+        )
+    }
+}
+
+pub fn soundfont_to_deluge(
+    sf: &SoundFont,
+    samples: &mut SampleRegistry,
+    ix: usize,
+    prefix: &str,
+    options: &ConvertOptions,
+) -> Vec<deluge::Sound> {
+    let preset = &sf.presets[ix];
+    info!("Preset: {}", preset.name);
+    let PresetLayers { layers, crossfade } = preset_layers(sf, ix, options);
+    // Map layers to oscs
+    let mut oscs: Vec<Vec<OscRange>> = layers
+        .iter()
+        .map(|l| osc_ranges(sf, l, options.bake_attenuation))
+        .collect();
+
+    // The deluge has two oscs per sound, so extra layers are either dropped or go to more sounds
+    if oscs.len() > 2 && options.extra_layers == ExtraLayers::Drop {
+        warn!(
+            "{} has more osc than the deluge has, dropping {} of them",
+            preset.name,
+            oscs.len() - 2
+        );
+        oscs.truncate(2);
+    }
+    // The attenuation common to all ranges goes to the volume, the rest is optionally baked into the samples
+    let mut attenuation = oscs
+        .iter()
+        .flatten()
+        .map(|r| r.attenuation)
+        .reduce(f32::min)
+        .unwrap_or(0.0);
+    if options.bake_attenuation {
+        // Turn the baked samples up as far as they go, and the volume down by as much
+        attenuation += headroom(sf, &oscs, attenuation);
+    }
+    let baked_attenuation = options.bake_attenuation.then_some(attenuation);
+    let deluge_oscs: Vec<deluge::Osc> = oscs
+        .iter()
+        .map(|osc| sample_osc(sf, samples, osc, baked_attenuation, options.sample_rate))
+        .collect();
+    println!("==== {} ====", preset.name);
+    let regions: Vec<&Region> = layers.iter().flatten().collect();
+    let mut default_params_builder = deluge::DefaultParamsBuilder::default();
+    if attenuation > 0.0 {
+        default_params_builder.volume(deluge::volume_to_value(attenuation));
+    }
+    let lpf = lpf_params(&regions);
+    if let Some((frequency, resonance)) = &lpf {
+        default_params_builder
+            .lpf_frequency(frequency.clone())
+            .lpf_resonance(resonance.clone());
+    }
+    let mut patch_cables = vec![];
+    add_envelopes(
+        &regions,
+        options.envelope_aggregation,
+        &mut default_params_builder,
+        &mut patch_cables,
+    );
+    add_lfos(&regions, &mut default_params_builder, &mut patch_cables);
+    let knobs = add_modulators(&regions, &mut default_params_builder, &mut patch_cables);
+    let chorus = add_pan_and_sends(sf, &regions, &mut default_params_builder);
+    if let Some(osc1_soft) = crossfade {
+        // Velocity fades out the soft layer and fades in the loud one
+        let amount = if osc1_soft { 50.0 } else { -50.0 };
+        patch_cables.push(patch_cable(
+            deluge::Source::Velocity,
+            deluge::Destination::Osc1Volume,
+            deluge::Value::from_patch_amount(-amount),
+        ));
+        patch_cables.push(patch_cable(
+            deluge::Source::Velocity,
+            deluge::Destination::Osc2Volume,
+            deluge::Value::from_patch_amount(amount),
+        ));
+    }
+    default_params_builder.patch_cables(
+        deluge::PatchCablesBuilder::default()
+            .patch_cable(patch_cables)
             .build()
             .unwrap(),
     );
-    sound_builder.default_params(default_params_builder.build().unwrap());
     let mut preset_name = prefix.to_owned();
     preset_name.push_str(&preset.name);
-    sound_builder.name(preset_name.to_owned());
-    sound_builder.build().unwrap()
+    if deluge_oscs.is_empty() {
+        warn!("{} has no samples, skipping", preset.name);
+    }
+    // Zones that all share an exclusive class cut each other off, like a mono synth
+    let first_class = regions.first().and_then(|r| exclusive_class(r));
+    let mono = first_class.is_some() && regions.iter().all(|r| exclusive_class(r) == first_class);
+    let num_sounds = deluge_oscs.len().div_ceil(2);
+    let mut sounds = vec![];
+    for (sound_ix, pair) in deluge_oscs.chunks(2).enumerate() {
+        let mut sound_builder = deluge::SoundBuilder::default();
+        sound_builder.firmware_version(Some("3.1.3".to_string()));
+        sound_builder.earliest_compatible_firmware(Some("3.1.0-beta".to_string()));
+        if lpf.is_some() {
+            // The SoundFont filter is two pole
+            sound_builder.lpf_mode(Some(deluge::LpfMode::Mode12dB));
+        }
+        if mono {
+            sound_builder.polyphonic(deluge::Polyphony::Mono);
+        }
+        if chorus {
+            sound_builder.mod_fx_type(deluge::ModFxType::Chorus);
+        }
+        let mut params_builder = default_params_builder.clone();
+        sound_builder.osc1(pair[0].clone());
+        params_builder.osc1_volume(deluge::Value(0x7FFFFFFF));
+        if let Some(osc2) = pair.get(1) {
+            sound_builder.osc2(osc2.clone());
+            params_builder.osc2_volume(deluge::Value(0x7FFFFFFF));
+        }
+        sound_builder
+            .default_params(params_builder.build().unwrap())
+            .midi_knobs(midi_knobs(knobs.clone()));
+        if num_sounds > 1 {
+            // Name them "Preset A", "Preset B" etc
+            sound_builder.name(format!("{} {}", preset_name, sound_suffix(sound_ix)));
+        } else {
+            sound_builder.name(preset_name.to_owned());
+        }
+        sounds.push(sound_builder.build().unwrap());
+    }
+    sounds
+}
+
+/// Letters for the sounds of a preset, "A" to "Z" and then "AA", "AB" etc like spreadsheet columns
+fn sound_suffix(ix: usize) -> String {
+    let mut suffix = vec![];
+    let mut n = ix + 1;
+    while n > 0 {
+        n -= 1;
+        suffix.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    suffix.iter().rev().collect()
+}
+
+/// The exclusive class of the region, None if it doesn't have one
+fn exclusive_class(region: &Region) -> Option<i16> {
+    match get_zone_generator!(&region.generators, Generator::ExclusiveClass(_)) {
+        Some(Generator::ExclusiveClass(class)) if class != 0 => Some(class),
+        _ => None,
+    }
+}
+
+/// Kit rows that share an exclusive class cut each other off. The deluge only has one choke group,
+/// so it goes to the class with the most rows.
+fn choke_class(regions: &[&Region]) -> Option<i16> {
+    let mut classes: Vec<(i16, usize)> = vec![];
+    for class in regions.iter().filter_map(|r| exclusive_class(r)) {
+        match classes.iter_mut().find(|(c, _)| *c == class) {
+            Some((_, count)) => *count += 1,
+            None => classes.push((class, 1)),
+        }
+    }
+    classes.retain(|(_, count)| *count > 1);
+    let choke_class = classes
+        .iter()
+        .max_by_key(|(class, count)| (*count, -class))
+        .map(|(class, _)| *class);
+    for (class, _) in &classes {
+        if Some(*class) != choke_class {
+            warn!("Exclusive class {} can't have its own choke group", class);
+        }
+    }
+    choke_class
+}
+
+/// Regions covering more keys than this get a single kit row instead of one per key
+const MAX_KIT_ROW_KEYS: u8 = 12;
+
+/// The keys of the kit rows in order, with the region that plays each. Where several regions
+/// cover a key, the loudest velocity layer plays it, and then the one with the fewest keys. A
+/// region gets a row for every key it plays, unless it is wider than [MAX_KIT_ROW_KEYS], then it
+/// only gets one at its lowest key.
+fn kit_keys<'a>(regions: &[&'a Region]) -> Vec<(u8, &'a Region)> {
+    let width = |r: &Region| r.key_range.1.saturating_sub(r.key_range.0) + 1;
+    let mut keys: Vec<(u8, &Region)> = vec![];
+    let mut collapsed: Vec<&Region> = vec![];
+    for key in 0..=127u8 {
+        let region = regions
+            .iter()
+            .filter(|r| (r.key_range.0..=r.key_range.1).contains(&key))
+            .min_by_key(|r| (std::cmp::Reverse(r.vel_range.1), width(r)));
+        let Some(&region) = region else {
+            continue;
+        };
+        if width(region) > MAX_KIT_ROW_KEYS {
+            if collapsed.iter().any(|r| std::ptr::eq(*r, region)) {
+                continue;
+            }
+            warn!(
+                "keys {}-{} play one sample, making one kit row for key {}",
+                region.key_range.0, region.key_range.1, key
+            );
+            collapsed.push(region);
+        }
+        keys.push((key, region));
+    }
+    keys
+}
+
+/// Converts a drum preset to a kit with one row per key
+pub fn soundfont_to_kit(
+    sf: &SoundFont,
+    samples: &mut SampleRegistry,
+    ix: usize,
+    prefix: &str,
+    options: &ConvertOptions,
+) -> deluge::Kit {
+    let preset = &sf.presets[ix];
+    info!("Kit: {}", preset.name);
+    let PresetLayers { layers, .. } = preset_layers(
+        sf,
+        ix,
+        &ConvertOptions {
+            velocity_crossfade: false,
+            ..options.clone()
+        },
+    );
+    let regions: Vec<&Region> = layers.iter().flatten().collect();
+    let choke_class = choke_class(&regions);
+    if let Some(class) = choke_class {
+        info!("{}: rows with exclusive class {} choke", preset.name, class);
+    }
+    let mut rows = vec![];
+    for (key, region) in kit_keys(&regions) {
+        let mut range = osc_ranges(sf, std::slice::from_ref(region), false).remove(0);
+        // Kit rows play at note 60, so transpose it to the pitch of the drum key
+        let (transpose, cents) = tuning(sf, region, key);
+        range.transpose = transpose + key as i32 - 60;
+        range.cents = cents;
+        let mut name = sf.samples[stereo_sample(sf, region.sample)].name.clone();
+        if region.key_range.0 != region.key_range.1 {
+            name.push_str(&format!(" {}", key));
+        }
+        let attenuation = range.attenuation;
+        let osc = sample_osc(sf, samples, &[range], None, options.sample_rate);
+        println!("==== {} {} ====", preset.name, key);
+        let mut params_builder = deluge::DefaultParamsBuilder::default();
+        params_builder.osc1_volume(deluge::Value(0x7FFFFFFF));
+        if attenuation > 0.0 {
+            params_builder.volume(deluge::volume_to_value(attenuation));
+        }
+        let mut patch_cables = vec![];
+        add_envelopes(
+            &[region],
+            options.envelope_aggregation,
+            &mut params_builder,
+            &mut patch_cables,
+        );
+        add_lfos(&[region], &mut params_builder, &mut patch_cables);
+        let knobs = add_modulators(&[region], &mut params_builder, &mut patch_cables);
+        params_builder.patch_cables(
+            deluge::PatchCablesBuilder::default()
+                .patch_cable(patch_cables)
+                .build()
+                .unwrap(),
+        );
+        let chorus = add_pan_and_sends(sf, &[region], &mut params_builder);
+        let mut sound_builder = deluge::SoundBuilder::default();
+        if chorus {
+            sound_builder.mod_fx_type(deluge::ModFxType::Chorus);
+        }
+        if let Some((frequency, resonance)) = lpf_params(&[region]) {
+            params_builder
+                .lpf_frequency(frequency)
+                .lpf_resonance(resonance);
+            sound_builder.lpf_mode(Some(deluge::LpfMode::Mode12dB));
+        }
+        sound_builder
+            .osc1(osc)
+            .default_params(params_builder.build().unwrap())
+            .midi_knobs(midi_knobs(knobs))
+            .name(name);
+        if choke_class.is_some() && exclusive_class(region) == choke_class {
+            sound_builder.polyphonic(deluge::Polyphony::Choke);
+        }
+        rows.push(sound_builder.build().unwrap());
+    }
+    let mut kit_name = prefix.to_owned();
+    kit_name.push_str(&preset.name);
+    deluge::Kit::from_sounds(&kit_name, rows)
 }
 
 pub fn save_deluge_as_xml(sound: &deluge::Sound, folder: &Path) {
@@ -367,39 +1656,478 @@ pub fn save_deluge_as_xml(sound: &deluge::Sound, folder: &Path) {
     fs::write(folder.join(Path::new(&file_name)), xml).unwrap();
 }
 
-pub fn save_as_xml(sf: &SoundFont, folder: &Path, sample_folder: &Path, ix: usize, prefix: &str) {
-    info!("Writing xml to {} for {}", folder.display(), ix);
-    let sound = soundfont_to_deluge(sf, sample_folder, ix, prefix);
-    save_deluge_as_xml(&sound, folder);
+pub fn save_kit_as_xml(kit: &deluge::Kit, folder: &Path) {
+    let xml = kit.to_xml();
+    fs::create_dir_all(folder).unwrap();
+    let file_name = SoundFont::safe_name(&kit.name) + ".xml";
+    fs::write(folder.join(Path::new(&file_name)), xml).unwrap();
 }
 
-fn get_instrument_zones(sf: &SoundFont, ix: usize) -> Vec<Vec<Generator>> {
-    let mut zones = vec![];
-    let is_last = ix == sf.instruments.len() - 1;
-    let instrument = &sf.instruments[ix];
-    let bag_start = instrument.bag_index as usize;
-    let bag_end = if is_last {
-        sf.ibags.len()
+pub fn save_as_xml(
+    sf: &SoundFont,
+    folder: &Path,
+    kit_folder: &Path,
+    samples: &mut SampleRegistry,
+    ix: usize,
+    prefix: &str,
+    options: &ConvertOptions,
+) {
+    info!("Writing xml to {} for {}", folder.display(), ix);
+    if sf.presets[ix].bank == DRUM_BANK {
+        let kit = soundfont_to_kit(sf, samples, ix, prefix, options);
+        save_kit_as_xml(&kit, kit_folder);
+        return;
+    }
+    let sounds = soundfont_to_deluge(sf, samples, ix, prefix, options);
+    if sounds.len() > 1 && options.extra_layers == ExtraLayers::Kit {
+        let kit = deluge::Kit::from_sounds(&format!("{}{}", prefix, sf.presets[ix].name), sounds);
+        save_kit_as_xml(&kit, kit_folder);
     } else {
-        let next_instrument = &sf.instruments[ix + 1];
-        next_instrument.bag_index as usize
-    };
-    for bag_ix in bag_start..bag_end {
-        let is_last = ix == sf.ibags.len() - 1;
-        let bag = &sf.ibags[bag_ix];
-        let gen_start = bag.gen_ndx as usize;
-        let gen_end = if is_last {
-            sf.igens.len()
-        } else {
-            let next_bag = &sf.ibags[bag_ix + 1];
-            next_bag.gen_ndx as usize
-        };
-        let mut zone = vec![];
-        for gen_ix in gen_start..gen_end {
-            let gen = &sf.igens[gen_ix];
-            zone.push(*gen);
+        for sound in &sounds {
+            save_deluge_as_xml(sound, folder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundfont::{Sample, SoundFontInfo};
+
+    /// A sound font with mono samples at middle C over the (start, end) of the data
+    fn soundfont(samples: &[(u32, u32)], data: &[i16]) -> SoundFont {
+        SoundFont {
+            info: SoundFontInfo::default(),
+            samples: samples
+                .iter()
+                .map(|&(start, end)| Sample {
+                    name: format!("S{}", start),
+                    start,
+                    end,
+                    start_loop: start,
+                    end_loop: end,
+                    sample_rate: 44100,
+                    original_pitch: 60,
+                    correction: 0,
+                    sample_link: 0,
+                    sample_type: 1,
+                })
+                .collect(),
+            sample_data: data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            sample_data_24: None,
+            presets: vec![],
+            instruments: vec![],
+            igens: vec![],
+            pgens: vec![],
+            imods: vec![],
+            pmods: vec![],
+            ibags: vec![],
+            pbags: vec![],
         }
-        zones.push(zone);
     }
-    zones
+
+    fn region(sample: usize, vel_range: (u8, u8)) -> Region {
+        Region {
+            preset_zone: 0,
+            instrument: 0,
+            sample,
+            key_range: (0, 127),
+            vel_range,
+            generators: vec![],
+            modulators: vec![],
+        }
+    }
+
+    #[test]
+    fn test_velocity_layer() {
+        let regions = vec![
+            region(0, (0, 63)),
+            region(1, (64, 100)),
+            region(2, (101, 127)),
+        ];
+        let pick = |layer: VelocityLayer| {
+            let v = layer.velocity(&regions);
+            velocity_layer(&regions, v)
+                .iter()
+                .map(|r| r.sample)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pick(VelocityLayer::Loudest), vec![2]);
+        assert_eq!(pick(VelocityLayer::Softest), vec![0]);
+        assert_eq!(pick(VelocityLayer::Nominal(100)), vec![1]);
+        assert_eq!(pick(VelocityLayer::Widest), vec![0]);
+        assert_eq!("loudest".parse(), Ok(VelocityLayer::Loudest));
+        assert_eq!("64".parse(), Ok(VelocityLayer::Nominal(64)));
+        assert!("128".parse::<VelocityLayer>().is_err());
+    }
+
+    #[test]
+    fn test_envelope_aggregation() {
+        let low = Region {
+            key_range: (0, 59),
+            ..region(0, (0, 127))
+        };
+        let mid = Region {
+            key_range: (60, 63),
+            ..region(1, (0, 127))
+        };
+        let high = Region {
+            key_range: (64, 127),
+            ..region(2, (0, 127))
+        };
+        let values = [(&low, 1.0), (&mid, 2.0), (&high, 8.0)];
+        let aggregate = |s: &str| s.parse::<EnvelopeAggregation>().unwrap().aggregate(&values);
+        assert_eq!(aggregate("mean"), 11.0 / 3.0);
+        assert_eq!(aggregate("median"), 2.0);
+        assert!((aggregate("log-mean") - 16.0f32.cbrt()).abs() < 1e-5);
+        assert_eq!(aggregate("key-weighted"), (60.0 + 8.0 + 512.0) / 128.0);
+        assert_eq!(aggregate("middle-c"), 2.0);
+        assert_eq!(aggregate("max"), 8.0);
+        assert_eq!(EnvelopeAggregation::Maximum.aggregate(&[]), 0.0);
+        assert!("average".parse::<EnvelopeAggregation>().is_err());
+    }
+
+    #[test]
+    fn test_envelope_stages() {
+        let stages = EnvelopeStages {
+            delay: 0.5,
+            attack: 0.5,
+            hold: 1.0,
+            decay: 1.0,
+            sustain: 20.0,
+        };
+        assert_eq!(stages.level(0.25), 0.0);
+        assert_eq!(stages.level(0.75), 0.5);
+        assert_eq!(stages.level(1.5), 1.0);
+        assert!((stages.level(2.5) - 10.0f32.powf(-0.5)).abs() < 1e-6);
+        assert!((stages.level(5.0) - 0.1).abs() < 1e-6);
+        assert_eq!(stages.deviation(|t| stages.level(t)).0, 0.0);
+        // Folding the hold into the decay is furthest off when the hold ends
+        let folded = EnvelopeStages {
+            delay: 0.0,
+            attack: 1.0,
+            hold: 0.0,
+            decay: 2.0,
+            ..stages
+        };
+        let (deviation, at) = stages.deviation(|t| folded.level(t));
+        assert!((deviation - (1.0 - 10.0f32.powf(-0.5))).abs() < 0.01);
+        assert!((at - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_default_modulators() {
+        let region = Region {
+            modulators: ModList::defaults(),
+            ..region(0, (0, 127))
+        };
+        let mut patch_cables = vec![];
+        let mut params = deluge::DefaultParamsBuilder::default();
+        let knobs = add_modulators(&[&region], &mut params, &mut patch_cables);
+        assert_eq!(knobs.len(), 1);
+        let routes: Vec<_> = patch_cables
+            .iter()
+            .map(|c| (c.source().clone(), c.destination().clone()))
+            .collect();
+        assert_eq!(
+            routes,
+            vec![
+                (deluge::Source::Velocity, deluge::Destination::Volume),
+                (deluge::Source::Velocity, deluge::Destination::LpfFrequency),
+                (deluge::Source::Lfo1, deluge::Destination::Pitch),
+            ]
+        );
+        // Velocity takes the volume all the way down, as close as it gets to the concave curve
+        assert_eq!(
+            patch_cables[0],
+            patch_cable(
+                deluge::Source::Velocity,
+                deluge::Destination::Volume,
+                deluge::Value::from_patch_amount(50.0)
+            )
+        );
+        // The mod wheel vibrato gets the default vibrato LFO rate
+        assert_eq!(
+            params.build().unwrap(),
+            deluge::DefaultParamsBuilder::default()
+                .lfo1_rate(deluge::lfo_rate_to_value(8.176))
+                .build()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unmapped_lfo_modulator() {
+        // A controller other than the mod wheel to vibrato depth has no cable, so no LFO rate
+        let mod_wheel = ModList::defaults()[3].clone();
+        let region = Region {
+            modulators: vec![ModList {
+                src_oper: Modulator {
+                    index: SourceEnumeratorController::MidiCC(2),
+                    ..mod_wheel.src_oper
+                },
+                ..mod_wheel
+            }],
+            ..region(0, (0, 127))
+        };
+        let mut patch_cables = vec![];
+        let mut params = deluge::DefaultParamsBuilder::default();
+        assert!(add_modulators(&[&region], &mut params, &mut patch_cables).is_empty());
+        assert!(patch_cables.is_empty());
+        assert_eq!(
+            params.build().unwrap(),
+            deluge::DefaultParamsBuilder::default().build().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_volume_cable_depth() {
+        let velocity = ModList::defaults()[0].clone();
+        // The default concave curve is steeper than a full depth cable, so that's the closest
+        assert_eq!(volume_cable_depth(&velocity, 960.0), f32::INFINITY);
+        // A shallower curve gets a part of the depth, less than its 24 dB range since most of it
+        // is at the lowest velocities
+        let depth = volume_cable_depth(&velocity, 240.0);
+        assert!((depth - 12.65).abs() < 0.1, "{}", depth);
+        assert_eq!(
+            deluge::volume_depth_to_patch_amount(depth),
+            deluge::Value::from_patch_amount(50.0 * (1.0 - 10.0f32.powf(-depth / 20.0)))
+        );
+        // Getting quieter with velocity is a negative depth
+        assert!(volume_cable_depth(&velocity, -240.0) < 0.0);
+        // A linear curve over 12 dB
+        let linear = ModList {
+            src_oper: Modulator {
+                continuity: SourceType::Linear,
+                ..velocity.src_oper
+            },
+            ..velocity
+        };
+        let depth = volume_cable_depth(&linear, 120.0);
+        assert!((depth - 14.66).abs() < 0.1, "{}", depth);
+    }
+
+    #[test]
+    fn test_bake_attenuation() {
+        // A sample at half scale and one at full scale
+        let sf = soundfont(&[(0, 2), (2, 4)], &[16384, -100, 0, 32767]);
+        let zone = |sample: usize, keys: (u8, u8), attenuation: i16| Region {
+            key_range: keys,
+            generators: vec![Generator::InitialAttenuation(attenuation)],
+            ..region(sample, (0, 127))
+        };
+        let regions = vec![zone(0, (0, 59), 20), zone(0, (60, 127), 80)];
+        // Without baking, the attenuation doesn't split the ranges and the lowest goes to volume
+        let ranges = osc_ranges(&sf, &regions, false);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].attenuation, 2.0);
+        assert_eq!(osc_ranges(&sf, &regions, true).len(), 2);
+
+        // The full scale one is played 8 dB quieter, so the peaks are 6 and 8 dB below full scale
+        let regions = vec![zone(0, (0, 59), 0), zone(1, (60, 127), 80)];
+        let oscs = vec![osc_ranges(&sf, &regions, true)];
+        let headroom = headroom(&sf, &oscs, 0.0);
+        assert!((headroom - 6.02).abs() < 0.01, "{}", headroom);
+        // So the gains of the samples take the louder one to full scale, and keep the other 2 dB down
+        let gain = |range: &OscRange| 10.0f32.powf(-(range.attenuation - headroom) / 20.0);
+        assert!((gain(&oscs[0][0]) * 16384.0 - 32767.0).abs() < 2.0);
+        let quieter = gain(&oscs[0][1]);
+        assert!(quieter > 0.79 && quieter < 0.8, "{}", quieter);
+    }
+
+    #[test]
+    fn test_kit_keys() {
+        let zone = |sample: usize, keys: (u8, u8), vel_range: (u8, u8)| Region {
+            key_range: keys,
+            ..region(sample, vel_range)
+        };
+        let kick = zone(0, (35, 36), (64, 127));
+        let soft_kick = zone(1, (35, 35), (0, 63));
+        let snare = zone(2, (38, 38), (0, 127));
+        let everything = zone(3, (0, 127), (0, 127));
+        let keys: Vec<(u8, usize)> = kit_keys(&[&snare, &everything, &soft_kick, &kick])
+            .into_iter()
+            .map(|(key, r)| (key, r.sample))
+            .collect();
+        // The loudest and then narrowest region plays each key, and the wide one only gets a row
+        // at the first key it plays
+        assert_eq!(keys, vec![(0, 3), (35, 0), (36, 0), (38, 2)]);
+    }
+
+    #[test]
+    fn test_sound_suffix() {
+        assert_eq!(sound_suffix(0), "A");
+        assert_eq!(sound_suffix(25), "Z");
+        assert_eq!(sound_suffix(26), "AA");
+        assert_eq!(sound_suffix(27), "AB");
+        assert_eq!(sound_suffix(26 + 26 * 26), "AAA");
+    }
+
+    #[test]
+    fn test_choke_class() {
+        let drum = |class: i16| Region {
+            generators: vec![Generator::ExclusiveClass(class)],
+            ..region(0, (0, 127))
+        };
+        let (kick, hat, hat2, hat3, cuica, cuica2) =
+            (drum(0), drum(1), drum(1), drum(1), drum(2), drum(2));
+        assert_eq!(choke_class(&[&kick, &hat]), None);
+        assert_eq!(choke_class(&[&kick, &hat, &hat2]), Some(1));
+        assert_eq!(choke_class(&[&cuica, &hat, &cuica2, &hat2]), Some(1));
+        assert_eq!(choke_class(&[&cuica, &hat, &cuica2, &hat2, &hat3]), Some(1));
+        assert_eq!(choke_class(&[&hat, &cuica, &cuica2]), Some(2));
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        assert_eq!(SampleRate::Keep.output_rate(22050), 22050);
+        assert_eq!(SampleRate::Keep.output_rate(96000), 44100);
+        assert_eq!(SampleRate::Keep.output_rate(200), 44100);
+        assert_eq!(
+            "48000".parse::<SampleRate>().unwrap().output_rate(22050),
+            48000
+        );
+        assert!("96000".parse::<SampleRate>().is_err());
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_name(&mut used, "Piano"), "Piano.wav");
+        assert_eq!(unique_name(&mut used, "piano"), "piano_2.wav");
+        assert_eq!(unique_name(&mut used, "Piano"), "Piano_3.wav");
+        assert_eq!(unique_name(&mut used, "Piano_2"), "Piano_2_2.wav");
+    }
+
+    #[test]
+    fn test_sd_root_file_name() {
+        let samples = SampleRegistry::in_sd_root(Path::new("/media/DELUGE"), "My/Font");
+        let path = samples.folder.join("Piano.wav");
+        assert_eq!(path, Path::new("/media/DELUGE/SAMPLES/My_Font/Piano.wav"));
+        assert_eq!(samples.file_name(&path), "SAMPLES/My_Font/Piano.wav");
+        let samples = SampleRegistry::new(Path::new("out/SAMPLES"));
+        assert_eq!(
+            samples.file_name(&samples.folder.join("Piano.wav")),
+            "out/SAMPLES/Piano.wav"
+        );
+    }
+
+    #[test]
+    fn test_split_cents() {
+        assert_eq!(split_cents(0), (0, 0));
+        assert_eq!(split_cents(1234), (12, 34));
+        assert_eq!(split_cents(1270), (13, -30));
+        assert_eq!(split_cents(-1270), (-13, 30));
+        assert_eq!(split_cents(-49), (0, -49));
+    }
+
+    #[test]
+    fn test_tuning() {
+        let mut sf = soundfont(&[(0, 2)], &[0, 0]);
+        let zone = |generators: Vec<Generator>| Region {
+            generators,
+            ..region(0, (0, 127))
+        };
+        // A sample at middle C
+        assert_eq!(tuning(&sf, &zone(vec![]), 60), (0, 0));
+        // Coarse, fine and the sample correction add up, carrying past 50 cents
+        sf.samples[0].correction = 30;
+        let region = zone(vec![Generator::CoarseTune(2), Generator::FineTune(40)]);
+        assert_eq!(tuning(&sf, &region, 60), (3, -30));
+        let region = zone(vec![Generator::CoarseTune(-1), Generator::FineTune(-90)]);
+        assert_eq!(tuning(&sf, &region, 60), (-2, 40));
+        // The root key of the sample is moved to middle C, and the zone can override it
+        sf.samples[0].correction = 0;
+        sf.samples[0].original_pitch = 69;
+        assert_eq!(tuning(&sf, &zone(vec![]), 60), (-9, 0));
+        let region = zone(vec![Generator::OverridingRootKey(48)]);
+        assert_eq!(tuning(&sf, &region, 60), (12, 0));
+        // -1 doesn't override
+        let region = zone(vec![Generator::OverridingRootKey(-1)]);
+        assert_eq!(tuning(&sf, &region, 60), (-9, 0));
+        // Unpitched samples play at the original rate on middle C
+        sf.samples[0].original_pitch = 255;
+        sf.samples[0].correction = -20;
+        let region = zone(vec![Generator::FineTune(-40)]);
+        assert_eq!(tuning(&sf, &region, 60), (-1, 40));
+    }
+
+    #[test]
+    fn test_overlapping_ranges() {
+        let sf = soundfont(&[(0, 2), (2, 4)], &[0, 0, 0, 0]);
+        let zone = |sample: usize, keys: (u8, u8)| Region {
+            key_range: keys,
+            ..region(sample, (0, 127))
+        };
+        let ranges = |regions: &[Region]| -> Vec<(usize, u8)> {
+            osc_ranges(&sf, regions, false)
+                .iter()
+                .map(|r| (r.region.sample, r.high))
+                .collect()
+        };
+        // Starting below the previous range only gets the keys above it
+        assert_eq!(
+            ranges(&[zone(0, (0, 30)), zone(1, (40, 60)), zone(0, (31, 70))]),
+            vec![(0, 39), (1, 60), (0, 70)]
+        );
+        // Starting inside it cuts the previous range short
+        assert_eq!(
+            ranges(&[zone(0, (0, 60)), zone(1, (40, 50))]),
+            vec![(0, 39), (1, 50)]
+        );
+        // Covered ranges are skipped
+        assert_eq!(
+            ranges(&[zone(0, (0, 30)), zone(1, (31, 60)), zone(0, (20, 40))]),
+            vec![(0, 30), (1, 60)]
+        );
+        // The same sample extends the range, but never shrinks it
+        assert_eq!(
+            ranges(&[zone(0, (40, 60)), zone(0, (30, 50))]),
+            vec![(0, 60)]
+        );
+    }
+
+    #[test]
+    fn test_scale_tuning_ranges() {
+        let sf = soundfont(&[(0, 2)], &[0, 0]);
+        let zone = |scale_tuning: i16| Region {
+            key_range: (48, 51),
+            generators: vec![Generator::ScaleTuning(scale_tuning)],
+            ..region(0, (0, 127))
+        };
+        // Played at key k, a range sounds (k - 60) * 100 + 100 * transpose + cents from the root
+        let pitches = |regions: &[Region]| -> Vec<(u8, i32)> {
+            let ranges = osc_ranges(&sf, regions, false);
+            ranges
+                .iter()
+                .map(|r| {
+                    assert!(r.cents.abs() <= 50);
+                    (
+                        r.high,
+                        (r.high as i32 - 60) * 100 + 100 * r.transpose + r.cents,
+                    )
+                })
+                .collect()
+        };
+        // Every key plays the sample at its root pitch
+        let regions = [zone(0)];
+        assert_eq!(pitches(&regions), vec![(48, 0), (49, 0), (50, 0), (51, 0)]);
+        // Every key is 50 cents above the one below
+        let regions = [zone(50)];
+        assert_eq!(
+            pitches(&regions),
+            vec![(48, -600), (49, -550), (50, -500), (51, -450)]
+        );
+        // Normal tuning keeps one range
+        let regions = [zone(100)];
+        assert_eq!(osc_ranges(&sf, &regions, false).len(), 1);
+    }
+
+    #[test]
+    fn test_velocity_layer_gap() {
+        // Nothing plays at 100, so the closest layer is used
+        let regions = vec![region(0, (0, 63)), region(1, (110, 127))];
+        let v = VelocityLayer::Nominal(100).velocity(&regions);
+        assert_eq!(v, 110);
+    }
 }
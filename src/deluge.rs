@@ -225,6 +225,26 @@ lazy_static! {
         -11.4, -10.7, -10.1, -9.5, -8.9, -8.3, -7.8, -7.2, -6.7, -6.2, -5.7, -5.3, -4.8, -4.3,
         -3.9, -3.5, -3.0, -2.6, -2.2, -1.8, -1.5, -1.1, -0.7, -0.4, 0.0,
     ];
+    static ref LFO_RATE_LUT: Vec<f32> = vec![
+        0.005, 0.006, 0.007, 0.009, 0.012, 0.014, 0.018, 0.023, 0.028, 0.035, 0.044, 0.055, 0.069,
+        0.086, 0.107, 0.134, 0.168, 0.210, 0.262, 0.328, 0.410, 0.512, 0.640, 0.800, 1.00, 1.25,
+        1.56, 1.95, 2.44, 3.05, 3.81, 4.77, 5.96, 7.45, 9.0, 12.0, 15.0, 18.0, 23.0, 28.0, 36.0,
+        44.0, 56.0, 69.0, 87.0, 108.0, 136.0, 169.0, 212.0, 265.0, 331.0,
+    ];
+    static ref PITCH_DEPTH_LUT: Vec<f32> = vec![
+        0.0, 3.0, 10.0, 20.0, 34.0, 52.0, 74.0, 99.0, 129.0, 162.0, 198.0, 239.0, 283.0, 331.0,
+        383.0, 438.0, 498.0, 561.0, 627.0, 698.0, 772.0, 850.0, 932.0, 1018.0, 1107.0, 1200.0,
+        1298.0, 1400.0, 1506.0, 1615.0, 1729.0, 1846.0, 1967.0, 2092.0, 2220.0, 2353.0, 2489.0,
+        2629.0, 2773.0, 2920.0, 3072.0, 3227.0, 3386.0, 3549.0, 3716.0, 3886.0, 4060.0, 4238.0,
+        4420.0, 4606.0, 4796.0,
+    ];
+    // LP Cutoff for 9-49, below 10 the cutoff stays at 53 Hz and 50 is fully open
+    static ref LPF_LUT: Vec<f32> = vec![
+        53.0, 61.0, 71.0, 83.0, 97.0, 113.0, 131.0, 153.0, 178.0, 208.0, 242.0, 281.0, 328.0,
+        381.0, 444.0, 516.0, 600.0, 698.0, 812.0, 945.0, 1100.0, 1300.0, 1500.0, 1700.0, 2000.0,
+        2300.0, 2700.0, 3100.0, 3700.0, 4300.0, 4900.0, 5700.0, 6700.0, 7700.0, 9000.0, 10500.0,
+        12100.0, 14100.0, 16400.0, 19000.0, 22100.0,
+    ];
 }
 
 pub fn duration_to_value(t: std::time::Duration, lut: &[std::time::Duration]) -> Value {
@@ -291,6 +311,83 @@ pub fn release_to_value(t: std::time::Duration) -> Value {
     duration_to_value(t, &RELEASE_LUT)
 }
 
+/// Sound volume that is `db` quieter than the default volume of 40
+pub fn volume_to_value(db: f32) -> Value {
+    level_to_value(LEVEL_LUT[40] - db, &LEVEL_LUT)
+}
+
+/// Reverb amount or mod FX depth for an effects send of 0-100%
+pub fn send_to_value(pct: f32) -> Value {
+    fractional_to_value(pct.clamp(0.0, 100.0) / 2.0)
+}
+
+fn fractional_to_value(v: f32) -> Value {
+    let ratio = (v - 25.0) / 25.0;
+    let iv = (ratio * i32::MAX as f32).round() as i32;
+    Value(iv as u32)
+}
+
+pub fn lpf_frequency_to_value(f: f32) -> Value {
+    let lut = &LPF_LUT;
+    if f <= lut[0] {
+        return Value::from_deluge_val(9);
+    }
+    let mut ix = 1;
+    while ix < lut.len() {
+        let a_val = lut[ix - 1];
+        let b_val = lut[ix];
+        if b_val > f {
+            // LERP in the log domain since the steps are roughly exponential
+            let d = (f.ln() - a_val.ln()) / (b_val.ln() - a_val.ln());
+            return fractional_to_value(9.0 + (ix - 1) as f32 + d);
+        }
+        ix += 1;
+    }
+    Value::from_deluge_val(50)
+}
+
+/// LFO rate in Hz. Measurements in notes.txt agree with the table (20 => 0.4 Hz, 25 => 1.3 Hz, 30 => 4 Hz).
+pub fn lfo_rate_to_value(hz: f32) -> Value {
+    level_to_value(hz, &LFO_RATE_LUT)
+}
+
+/// Patch cable amount to volume for a tremolo of `db`, a full amount takes the level down to silence
+pub fn volume_depth_to_patch_amount(db: f32) -> Value {
+    let ratio = 1.0 - 10.0f32.powf(-db.abs() / 20.0);
+    Value::from_patch_amount((ratio * 50.0).copysign(db))
+}
+
+/// Patch cable amount to pitch that gives a depth of `cents`
+pub fn pitch_depth_to_patch_amount(cents: f32) -> Value {
+    let lut = &PITCH_DEPTH_LUT;
+    let c = cents.abs();
+    let mut v = 50.0;
+    for ix in 1..lut.len() {
+        if lut[ix] > c {
+            v = (ix - 1) as f32 + (c - lut[ix - 1]) / (lut[ix] - lut[ix - 1]);
+            break;
+        }
+    }
+    Value::from_patch_amount(v.copysign(cents))
+}
+
+/// Patch cable amount to LPF frequency that moves the cutoff by about `cents`.
+/// Steps 9-49 of the cutoff cover 53 Hz - 22.1 kHz, about 260 cents per step.
+pub fn lpf_cents_to_patch_amount(cents: f32) -> Value {
+    Value::from_patch_amount(cents / 260.0)
+}
+
+/// Maps the height of the resonance peak in dB, 30 dB or more is full resonance
+pub fn lpf_resonance_to_value(db: f32) -> Value {
+    fractional_to_value((db / 30.0).clamp(0.0, 1.0) * 50.0)
+}
+
+/// Pan from -50% (left) to 50% (right)
+pub fn pan_to_value(p: f32) -> Value {
+    let ratio = p.clamp(-50.0, 50.0) / 50.0;
+    Value(((ratio as f64 * i32::MAX as f64).round() as i32) as u32)
+}
+
 #[derive(Default, Clone, Debug, Eq, PartialEq)]
 pub struct Value(pub u32);
 
@@ -307,6 +404,12 @@ impl Value {
         let iv = (ratio * i32::MAX as f32).round() as i32;
         Value(iv as u32)
     }
+
+    /// Patch cable amounts go from -50 to 50, 50 is 0x3FFFFFE8
+    pub fn from_patch_amount(v: f32) -> Value {
+        let iv = (v.clamp(-50.0, 50.0) as f64 / 50.0 * 0x3FFFFFE8 as f64).round() as i32;
+        Value(iv as u32)
+    }
 }
 
 impl yaserde::YaSerialize for Value {
@@ -349,7 +452,7 @@ impl yaserde::YaDeserialize for Value {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum OscType {
     #[yaserde(rename = "analogSaw")]
     AnalogSaw,
@@ -364,6 +467,7 @@ pub enum OscType {
     #[yaserde(rename = "saw")]
     Saw,
     #[yaserde(rename = "sine")]
+    #[default]
     Sine,
     #[yaserde(rename = "square")]
     Square,
@@ -371,12 +475,6 @@ pub enum OscType {
     Triangle,
 }
 
-impl Default for OscType {
-    fn default() -> OscType {
-        OscType::Sine
-    }
-}
-
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
 pub struct Zone {
@@ -458,7 +556,7 @@ impl Default for Osc {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum LfoType {
     #[yaserde(rename = "saw")]
     Saw,
@@ -467,15 +565,10 @@ pub enum LfoType {
     #[yaserde(rename = "square")]
     Square,
     #[yaserde(rename = "triangle")]
+    #[default]
     Triangle,
 }
 
-impl Default for LfoType {
-    fn default() -> LfoType {
-        LfoType::Triangle
-    }
-}
-
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
 pub struct Lfo {
@@ -485,22 +578,17 @@ pub struct Lfo {
     sync_level: Option<i32>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum Mode {
     #[yaserde(rename = "ringmod")]
     Ringmod,
     #[yaserde(rename = "subtractive")]
+    #[default]
     Subtractive,
     #[yaserde(rename = "fm")]
     Fm,
 }
 
-impl Default for Mode {
-    fn default() -> Mode {
-        Mode::Subtractive
-    }
-}
-
 #[derive(Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
 pub struct Unison {
@@ -537,8 +625,9 @@ impl Default for Delay {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub enum LpfMode {
+    #[default]
     Mode24dB,
     Mode24dBDrive,
     Mode12dB,
@@ -589,15 +678,10 @@ impl yaserde::YaDeserialize for LpfMode {
     }
 }
 
-impl Default for LpfMode {
-    fn default() -> LpfMode {
-        LpfMode::Mode24dB
-    }
-}
-
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum ModFxType {
     #[yaserde(rename = "none")]
+    #[default]
     None,
     #[yaserde(rename = "chorus")]
     Chorus,
@@ -607,12 +691,6 @@ pub enum ModFxType {
     Phaser,
 }
 
-impl Default for ModFxType {
-    fn default() -> ModFxType {
-        ModFxType::None
-    }
-}
-
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
 pub struct Envelope {
@@ -626,7 +704,7 @@ pub struct Envelope {
     release: Value,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum Source {
     #[yaserde(rename = "aftertouch")]
     Aftertouch,
@@ -643,18 +721,13 @@ pub enum Source {
     #[yaserde(rename = "note")]
     Note,
     #[yaserde(rename = "velocity")]
+    #[default]
     Velocity,
     #[yaserde(rename = "random")]
     Random,
 }
 
-impl Default for Source {
-    fn default() -> Source {
-        Source::Velocity
-    }
-}
-
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum Destination {
     #[yaserde(rename = "arpRate")]
     ArpRate,
@@ -751,6 +824,7 @@ pub enum Destination {
     #[yaserde(rename = "trebleFreq")]
     TrebleFreq,
     #[yaserde(rename = "volume")]
+    #[default]
     Volume,
     #[yaserde(rename = "volumePostFX")]
     VolumePostFx,
@@ -758,12 +832,6 @@ pub enum Destination {
     VolumePostReverbSend,
 }
 
-impl Default for Destination {
-    fn default() -> Destination {
-        Destination::Volume
-    }
-}
-
 #[derive(YaSerialize, Clone, Builder, YaDeserialize, Debug, Eq, PartialEq)]
 pub struct PatchCable {
     #[yaserde(attribute)]
@@ -772,6 +840,42 @@ pub struct PatchCable {
     destination: Destination,
     #[yaserde(attribute)]
     amount: Value,
+    #[yaserde(rename = "depthControlledBy")]
+    #[builder(default)]
+    depth_controlled_by: Option<DepthControlledBy>,
+}
+
+impl PatchCable {
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    /// Patches another source to the amount of this cable
+    pub fn add_depth_control(&mut self, source: Source, amount: Value) {
+        self.depth_controlled_by
+            .get_or_insert_with(DepthControlledBy::default)
+            .patch_cable
+            .push(DepthCable { source, amount });
+    }
+}
+
+/// The sources that control the amount of a patch cable
+#[derive(Default, YaSerialize, Clone, YaDeserialize, Debug, Eq, PartialEq)]
+pub struct DepthControlledBy {
+    #[yaserde(rename = "patchCable")]
+    patch_cable: Vec<DepthCable>,
+}
+
+#[derive(Default, YaSerialize, Clone, YaDeserialize, Debug, Eq, PartialEq)]
+pub struct DepthCable {
+    #[yaserde(attribute)]
+    source: Source,
+    #[yaserde(attribute)]
+    amount: Value,
 }
 
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
@@ -898,6 +1002,7 @@ impl Default for DefaultParams {
             source: Source::Velocity,
             destination: Destination::Volume,
             amount: Value(0x3FFFFFE8),
+            depth_controlled_by: None,
         };
         DefaultParams {
             arpeggiator_gate: Value(0x00000000),
@@ -958,14 +1063,27 @@ impl Default for DefaultParams {
     }
 }
 
+/// A MIDI CC learned to a parameter, or to the amount of a patch cable from `patch_amount_from_source`
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
-pub struct MidiKnob {}
+pub struct MidiKnob {
+    #[yaserde(attribute)]
+    channel: u8,
+    #[yaserde(attribute, rename = "ccNumber")]
+    cc_number: u8,
+    #[yaserde(attribute)]
+    relative: u8,
+    #[yaserde(attribute, rename = "controlsParam")]
+    controls_param: Destination,
+    #[yaserde(attribute, rename = "patchAmountFromSource")]
+    patch_amount_from_source: Option<Source>,
+}
 
 #[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[yaserde(rename_all = "camelCase")]
 #[builder(default)]
 pub struct MidiKnobs {
+    #[yaserde(rename = "midiKnob")]
     midi_knob: Vec<MidiKnob>,
 }
 
@@ -982,6 +1100,7 @@ pub struct ModKnob {
 #[yaserde(rename_all = "camelCase")]
 #[builder(default)]
 pub struct ModKnobs {
+    #[yaserde(rename = "modKnob")]
     mod_knob: Vec<ModKnob>,
 }
 
@@ -1058,18 +1177,13 @@ impl Default for ModKnobs {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, YaSerialize, YaDeserialize, Default)]
 pub enum ArpeggiatorMode {
     #[yaserde(rename = "off")]
+    #[default]
     Off,
 }
 
-impl Default for ArpeggiatorMode {
-    fn default() -> ArpeggiatorMode {
-        ArpeggiatorMode::Off
-    }
-}
-
 #[derive(Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
 #[builder(default)]
 pub struct Arpeggiator {
@@ -1091,21 +1205,18 @@ impl Default for Arpeggiator {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub enum Polyphony {
     Auto,
     Mono,
     Legato,
+    #[default]
     Poly,
+    /// Kit rows only, playing a row cuts off all other rows in choke mode
+    Choke,
     Integer(u32),
 }
 
-impl Default for Polyphony {
-    fn default() -> Polyphony {
-        Polyphony::Poly
-    }
-}
-
 impl yaserde::YaSerialize for Polyphony {
     fn serialize<W: Write>(&self, writer: &mut yaserde::ser::Serializer<W>) -> Result<(), String> {
         match *self {
@@ -1121,6 +1232,9 @@ impl yaserde::YaSerialize for Polyphony {
             Polyphony::Poly => writer
                 .write(xml::writer::XmlEvent::characters("poly"))
                 .map_err(|e| e.to_string()),
+            Polyphony::Choke => writer
+                .write(xml::writer::XmlEvent::characters("choke"))
+                .map_err(|e| e.to_string()),
             Polyphony::Integer(x) => writer
                 .write(xml::writer::XmlEvent::characters(&x.to_string()))
                 .map_err(|e| e.to_string()),
@@ -1149,6 +1263,7 @@ impl yaserde::YaDeserialize for Polyphony {
                 "mono" => Ok(Polyphony::Mono),
                 "legato" => Ok(Polyphony::Legato),
                 "poly" => Ok(Polyphony::Poly),
+                "choke" => Ok(Polyphony::Choke),
                 other => {
                     if let Ok(v) = other.parse::<u32>() {
                         Ok(Polyphony::Integer(v))
@@ -1234,72 +1349,84 @@ impl Default for Sound {
     }
 }
 
-impl Sound {
-    pub fn to_xml(&self) -> String {
-        let yaserde_cfg = yaserde::ser::Config {
-            perform_indent: false,
-            ..Default::default()
-        };
-        // serialize
-        let xml = to_string_with_config(self, &yaserde_cfg).unwrap();
-        // reformat
-        let mut out = String::new();
-        let mut indent_level = -1i32;
-        let mut in_string = false;
-        let mut _prev = None;
-        let mut chars = xml.chars().peekable();
-        while let Some(c) = chars.next() {
-            match c {
-                '>' => {
-                    out.push(c);
-                    if !in_string {
-                        out.push('\n');
-                        if indent_level > 0 {
-                            out.push_str(&"\t".repeat(indent_level as usize));
-                        }
-                    }
-                }
-                '<' => {
-                    if in_string {
-                        out.push(c);
-                    } else if chars.peek() == Some(&'/') {
-                        if out.ends_with('\t') {
-                            out.pop();
-                        }
-                        out.push(c);
-                    } else {
-                        out.push(c);
-                        indent_level += 1;
+/// Reformats serialized xml to look like the files the deluge writes
+fn format_xml(xml: &str) -> String {
+    let mut out = String::new();
+    let mut indent_level = -1i32;
+    let mut in_string = false;
+    // Element text is kept as is
+    let mut in_text = false;
+    let mut _prev = None;
+    let mut chars = xml.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_text && c != '<' {
+            out.push(c);
+            continue;
+        }
+        match c {
+            '>' => {
+                out.push(c);
+                if !in_string && matches!(chars.peek(), Some(x) if *x != '<') {
+                    in_text = true;
+                } else if !in_string {
+                    out.push('\n');
+                    if indent_level > 0 {
+                        out.push_str(&"\t".repeat(indent_level as usize));
                     }
                 }
-                '/' => {
-                    if !in_string {
-                        indent_level -= 1;
+            }
+            '<' => {
+                in_text = false;
+                if in_string {
+                    out.push(c);
+                } else if chars.peek() == Some(&'/') {
+                    if out.ends_with('\t') {
+                        out.pop();
                     }
                     out.push(c);
-                }
-                '"' => {
-                    in_string = !in_string;
+                } else {
                     out.push(c);
+                    indent_level += 1;
                 }
-                ' ' => {
-                    if in_string || chars.peek() == Some(&'/') {
-                        out.push(c);
-                    } else if indent_level > 0 {
-                        out.push('\n');
-                        out.push_str(&"\t".repeat(indent_level as usize));
-                    } else {
-                        out.push(c);
-                    }
+            }
+            '/' => {
+                if !in_string {
+                    indent_level -= 1;
                 }
-                _ => {
+                out.push(c);
+            }
+            '"' => {
+                in_string = !in_string;
+                out.push(c);
+            }
+            ' ' => {
+                if in_string || chars.peek() == Some(&'/') {
+                    out.push(c);
+                } else if indent_level > 0 {
+                    out.push('\n');
+                    out.push_str(&"\t".repeat(indent_level as usize));
+                } else {
                     out.push(c);
                 }
             }
-            _prev = Some(c);
+            _ => {
+                out.push(c);
+            }
         }
-        out = out.replace("utf-8", "UTF-8");
-        out
+        _prev = Some(c);
+    }
+    out.replace("utf-8", "UTF-8")
+}
+
+impl Sound {
+    pub fn to_xml(&self) -> String {
+        let yaserde_cfg = yaserde::ser::Config {
+            perform_indent: false,
+            ..Default::default()
+        };
+        // serialize
+        let xml = to_string_with_config(self, &yaserde_cfg).unwrap();
+        format_xml(&xml)
     }
 
     pub fn from_xml(file: &mut fs::File) -> Sound {
@@ -1309,6 +1436,148 @@ impl Sound {
     }
 }
 
+#[derive(Default, Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
+#[builder(default)]
+pub struct SoundSources {
+    #[yaserde(rename = "sound")]
+    sound: Vec<Sound>,
+}
+
+#[derive(Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
+#[builder(default)]
+pub struct KitParams {
+    #[yaserde(attribute, rename = "reverbAmount")]
+    reverb_amount: Value,
+    #[yaserde(attribute)]
+    volume: Value,
+    #[yaserde(attribute)]
+    pan: Value,
+    #[yaserde(attribute, rename = "lpfFrequency")]
+    lpf_frequency: Value,
+    #[yaserde(attribute, rename = "lpfResonance")]
+    lpf_resonance: Value,
+    #[yaserde(attribute, rename = "hpfFrequency")]
+    hpf_frequency: Value,
+    #[yaserde(attribute, rename = "hpfResonance")]
+    hpf_resonance: Value,
+    #[yaserde(attribute, rename = "modFXRate")]
+    mod_fx_rate: Value,
+    #[yaserde(attribute, rename = "modFXDepth")]
+    mod_fx_depth: Value,
+    #[yaserde(attribute, rename = "delayRate")]
+    delay_rate: Value,
+    #[yaserde(attribute, rename = "delayFeedback")]
+    delay_feedback: Value,
+    #[yaserde(attribute, rename = "stutterRate")]
+    stutter_rate: Value,
+    #[yaserde(attribute, rename = "sampleRateReduction")]
+    sample_rate_reduction: Value,
+    #[yaserde(attribute, rename = "bitCrush")]
+    bitcrush: Value,
+    equalizer: Equalizer,
+}
+
+// Values from a new kit saved on the deluge
+impl Default for KitParams {
+    fn default() -> KitParams {
+        KitParams {
+            reverb_amount: Value(0x80000000),
+            volume: Value(0x3504F334),
+            pan: Value(0x00000000),
+            lpf_frequency: Value(0x7FFFFFFF),
+            lpf_resonance: Value(0x80000000),
+            hpf_frequency: Value(0x80000000),
+            hpf_resonance: Value(0x80000000),
+            mod_fx_rate: Value(0xE0000000),
+            mod_fx_depth: Value(0x80000000),
+            delay_rate: Value(0x00000000),
+            delay_feedback: Value(0x80000000),
+            stutter_rate: Value(0x00000000),
+            sample_rate_reduction: Value(0x80000000),
+            bitcrush: Value(0x80000000),
+            equalizer: Equalizer {
+                bass: Value(0x00000000),
+                treble: Value(0x00000000),
+                bass_frequency: Value(0x00000000),
+                treble_frequency: Value(0x00000000),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Builder, YaSerialize, YaDeserialize, Debug, Eq, PartialEq)]
+#[yaserde(rename = "kit")]
+#[builder(default)]
+pub struct Kit {
+    #[yaserde(attribute, rename = "firmwareVersion")]
+    firmware_version: Option<String>,
+    #[yaserde(attribute, rename = "earliestCompatibleFirmware")]
+    earliest_compatible_firmware: Option<String>,
+    #[yaserde(attribute, rename = "lpfMode")]
+    lpf_mode: Option<LpfMode>,
+    #[yaserde(attribute, rename = "modFXType")]
+    mod_fx_type: ModFxType,
+    delay: Delay,
+    compressor: Option<Compressor>,
+    #[yaserde(rename = "defaultParams")]
+    default_params: KitParams,
+    #[yaserde(rename = "soundSources")]
+    sound_sources: SoundSources,
+    #[yaserde(rename = "selectedDrumIndex")]
+    selected_drum_index: Option<u32>,
+    #[yaserde(skip_serializing = true)]
+    pub name: String,
+}
+
+impl Default for Kit {
+    fn default() -> Kit {
+        Kit {
+            firmware_version: Some("3.1.5".to_string()),
+            earliest_compatible_firmware: Some("3.1.0-beta".to_string()),
+            lpf_mode: Some(LpfMode::default()),
+            mod_fx_type: ModFxType::default(),
+            delay: Delay::default(),
+            compressor: Some(Compressor::default()),
+            default_params: KitParams::default(),
+            sound_sources: SoundSources::default(),
+            selected_drum_index: Some(0),
+            name: String::new(),
+        }
+    }
+}
+
+impl Kit {
+    /// Creates a kit with one row per sound, the rows get the names of the sounds
+    pub fn from_sounds(name: &str, sounds: Vec<Sound>) -> Kit {
+        let sound = sounds
+            .into_iter()
+            .map(|mut sound| {
+                sound.firmware_version = None;
+                sound.earliest_compatible_firmware = None;
+                sound
+            })
+            .collect();
+        Kit {
+            sound_sources: SoundSources { sound },
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn rows(&self) -> &[Sound] {
+        &self.sound_sources.sound
+    }
+
+    pub fn to_xml(&self) -> String {
+        let yaserde_cfg = yaserde::ser::Config {
+            perform_indent: false,
+            ..Default::default()
+        };
+        let xml = to_string_with_config(self, &yaserde_cfg).unwrap();
+        format_xml(&xml)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1374,6 +1643,7 @@ mod tests {
             source: Source::Velocity,
             destination: Destination::Volume,
             amount: Value(0x3FFFFFE8),
+            depth_controlled_by: None,
         };
         let parsed: PatchCable = from_str(s).unwrap();
         assert_eq!(parsed, expected);
@@ -1387,6 +1657,7 @@ mod tests {
                 source: Source::Velocity,
                 destination: Destination::Volume,
                 amount: Value(0x3FFFFFE8),
+                depth_controlled_by: None,
             }],
         };
         let parsed: PatchCables = from_str(s).unwrap();
@@ -1412,6 +1683,99 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_kit() {
+        let sounds = vec![
+            SoundBuilder::default()
+                .name("Kick".to_string())
+                .build()
+                .unwrap(),
+            SoundBuilder::default()
+                .name("Snare".to_string())
+                .build()
+                .unwrap(),
+        ];
+        let kit = Kit::from_sounds("Drums", sounds);
+        let xml = kit.to_xml();
+        assert!(xml.contains("<kit"));
+        assert!(xml.contains("<soundSources>"));
+        assert!(xml.contains("<name>Snare</name>"));
+        assert!(xml.contains("<defaultParams"));
+        assert_eq!(kit.rows().len(), 2);
+    }
+
+    #[test]
+    fn test_choke() {
+        let sound = SoundBuilder::default()
+            .polyphonic(Polyphony::Choke)
+            .build()
+            .unwrap();
+        assert!(sound.to_xml().contains("polyphonic=\"choke\""));
+    }
+
+    #[test]
+    fn test_lpf() {
+        assert_eq!(lpf_frequency_to_value(20.0).to_deluge_val(), 9);
+        assert_eq!(lpf_frequency_to_value(600.0).to_deluge_val(), 25);
+        assert_eq!(lpf_frequency_to_value(1000.0).to_deluge_val(), 28);
+        assert_eq!(lpf_frequency_to_value(30000.0).to_deluge_val(), 50);
+        assert_eq!(lpf_resonance_to_value(0.0).to_deluge_val(), 0);
+        assert_eq!(lpf_resonance_to_value(15.0).to_deluge_val(), 25);
+        assert_eq!(lpf_resonance_to_value(96.0).to_deluge_val(), 50);
+    }
+
+    #[test]
+    fn test_patch_amounts() {
+        assert_eq!(pitch_depth_to_patch_amount(0.0), Value(0));
+        assert_eq!(
+            pitch_depth_to_patch_amount(1200.0),
+            Value::from_patch_amount(25.0)
+        );
+        assert_eq!(
+            pitch_depth_to_patch_amount(-1200.0),
+            Value::from_patch_amount(-25.0)
+        );
+        assert_eq!(
+            pitch_depth_to_patch_amount(12000.0),
+            Value::from_patch_amount(50.0)
+        );
+        assert_eq!(
+            lpf_cents_to_patch_amount(2600.0),
+            Value::from_patch_amount(10.0)
+        );
+    }
+
+    #[test]
+    fn test_lfo_rate() {
+        // From notes.txt
+        assert_eq!(lfo_rate_to_value(0.4).to_deluge_val(), 20);
+        assert_eq!(lfo_rate_to_value(1.3).to_deluge_val(), 25);
+        assert_eq!(lfo_rate_to_value(4.0).to_deluge_val(), 30);
+        assert_eq!(lfo_rate_to_value(1000.0).to_deluge_val(), 50);
+    }
+
+    #[test]
+    fn test_pan() {
+        assert_eq!(pan_to_value(0.0), Value(0x00000000));
+        assert_eq!(pan_to_value(50.0), Value(0x7FFFFFFF));
+        assert_eq!(pan_to_value(-50.0), Value(0x80000001));
+    }
+
+    #[test]
+    fn test_send() {
+        assert_eq!(send_to_value(0.0), Value::from_deluge_val(0));
+        assert_eq!(send_to_value(50.0), Value::from_deluge_val(25));
+        assert_eq!(send_to_value(100.0).to_deluge_val(), 50);
+    }
+
+    #[test]
+    fn test_volume() {
+        assert_eq!(volume_to_value(0.0).to_deluge_val(), 40);
+        // 6 dB down from -4.3 dB is between 27 (-10.7 dB) and 28 (-10.1 dB)
+        assert_eq!(volume_to_value(6.0).to_deluge_val(), 28);
+        assert_eq!(volume_to_value(144.0), Value::from_deluge_val(0));
+    }
+
     #[test]
     fn test_value() {
         assert_eq!(Value(0x80000000).to_deluge_val(), 0);
@@ -1420,5 +1784,8 @@ mod tests {
         assert_eq!(Value::from_deluge_val(0), Value(0x80000000));
         assert_eq!(Value::from_deluge_val(25), Value(0x00000000));
         assert_eq!(Value::from_deluge_val(50), Value(0x7FFFFFFF));
+        assert_eq!(Value::from_patch_amount(50.0), Value(0x3FFFFFE8));
+        assert_eq!(Value::from_patch_amount(0.0), Value(0x00000000));
+        assert_eq!(Value::from_patch_amount(-50.0), Value(0xC0000018));
     }
 }
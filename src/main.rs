@@ -1,3 +1,6 @@
+// The yaserde derives generate impls inside anonymous consts
+#![allow(non_local_definitions)]
+
 extern crate yaserde;
 #[macro_use]
 extern crate yaserde_derive;
@@ -7,13 +10,14 @@ extern crate lazy_static;
 
 pub mod convert;
 pub mod deluge;
+pub mod resample;
 pub mod soundfont;
 pub mod wav;
 
 use clap::{App, Arg};
 use soundfont::SoundFont;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 fn main() {
     env_logger::init();
@@ -46,6 +50,22 @@ fn main() {
                 .help("Sets the output folder to save synth xml to")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("KIT")
+                .short("k")
+                .long("kit-folder")
+                .takes_value(true)
+                .help("Sets the output folder to save kit xml to")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("SD_ROOT")
+                .long("sd-root")
+                .takes_value(true)
+                .conflicts_with_all(&["SAMPLES", "SYNTH", "KIT"])
+                .help("Sets the root of a Deluge SD card to save to, xml goes to SYNTHS and KITS and samples to SAMPLES/<font name>")
+                .required(false),
+        )
         .arg(
             Arg::with_name("PREFIX")
                 .short("p")
@@ -54,6 +74,55 @@ fn main() {
                 .help("Sets a prefix to prepend to synth xml file names")
                 .required(false),
         )
+        .arg(
+            Arg::with_name("IGNORE_COPYRIGHT")
+                .long("ignore-copyright")
+                .takes_value(false)
+                .help("Convert even if the copyright notice forbids redistribution")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("VELOCITY_LAYER")
+                .long("velocity-layer")
+                .takes_value(true)
+                .help("Which velocity layer to convert: loudest, softest, widest or a velocity (default 100)")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("VELOCITY_CROSSFADE")
+                .long("velocity-crossfade")
+                .takes_value(false)
+                .help("Put another velocity layer on osc2 and crossfade between them with velocity")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("BAKE_ATTENUATION")
+                .long("bake-attenuation")
+                .takes_value(false)
+                .help("Scale the samples by the attenuation of their zones, so key ranges keep their levels")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("EXTRA_LAYERS")
+                .long("extra-layers")
+                .takes_value(true)
+                .help("What to do with layers that don't fit in two oscs: drop (default), sounds or kit")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("ENVELOPE_AGGREGATION")
+                .long("envelope-aggregation")
+                .takes_value(true)
+                .help("How to combine the envelopes of the zones: mean (default), median, log-mean, key-weighted, middle-c or max")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("SAMPLE_RATE")
+                .long("sample-rate")
+                .takes_value(true)
+                .help("Sample rate of the written samples: keep (default, resamples rates the Deluge can't play to 44100), 44100 or 48000")
+                .required(false),
+        )
         .arg(
             Arg::with_name("DUMP")
                 .help("Dump info")
@@ -74,18 +143,104 @@ fn main() {
             println!("{:?}", synth);
         }
     } else {
-        let sf = SoundFont::parse_soundfont(&mut file);
+        let sf = match SoundFont::parse_soundfont(&mut file) {
+            Ok(sf) => sf,
+            Err(e) => {
+                eprintln!("Could not parse {}: {}", filename, e);
+                std::process::exit(1);
+            }
+        };
         if matches.is_present("DUMP") {
             println!("dumping");
             sf.dump();
         }
-        if let Some(xml_folder) = matches.value_of("SYNTH") {
+        let sd_root = matches.value_of("SD_ROOT").map(Path::new);
+        let xml_folder = match sd_root {
+            Some(root) => Some(root.join("SYNTHS")),
+            None => matches.value_of("SYNTH").map(PathBuf::from),
+        };
+        if let Some(xml_folder) = xml_folder {
+            if sf.info.forbids_redistribution() && !matches.is_present("IGNORE_COPYRIGHT") {
+                eprintln!(
+                    "Not converting {}, the copyright notice forbids redistribution: {}",
+                    filename,
+                    sf.info.copyright.as_deref().unwrap_or_default()
+                );
+                std::process::exit(1);
+            }
             // TODO: save all xmls
-            let sample_folder = matches.value_of("SAMPLES");
-            let samples = sample_folder.unwrap_or("SAMPLES");
+            let kits = match sd_root {
+                Some(root) => root.join("KITS"),
+                None => PathBuf::from(matches.value_of("KIT").unwrap_or("KITS")),
+            };
             let prefix = matches.value_of("PREFIX").unwrap_or("");
+            let mut options = convert::ConvertOptions {
+                velocity_crossfade: matches.is_present("VELOCITY_CROSSFADE"),
+                bake_attenuation: matches.is_present("BAKE_ATTENUATION"),
+                ..Default::default()
+            };
+            if let Some(layer) = matches.value_of("VELOCITY_LAYER") {
+                options.velocity_layer = match layer.parse() {
+                    Ok(layer) => layer,
+                    Err(e) => {
+                        eprintln!("Invalid velocity layer: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            if let Some(extra_layers) = matches.value_of("EXTRA_LAYERS") {
+                options.extra_layers = match extra_layers.parse() {
+                    Ok(extra_layers) => extra_layers,
+                    Err(e) => {
+                        eprintln!("Invalid extra layers: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            if let Some(aggregation) = matches.value_of("ENVELOPE_AGGREGATION") {
+                options.envelope_aggregation = match aggregation.parse() {
+                    Ok(aggregation) => aggregation,
+                    Err(e) => {
+                        eprintln!("Invalid envelope aggregation: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            if let Some(sample_rate) = matches.value_of("SAMPLE_RATE") {
+                options.sample_rate = match sample_rate.parse() {
+                    Ok(sample_rate) => sample_rate,
+                    Err(e) => {
+                        eprintln!("Invalid sample rate: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            let mut sample_registry = match sd_root {
+                Some(root) => {
+                    let font_name = match sf.info.name.as_deref().map(str::trim) {
+                        Some(name) if !name.is_empty() => name.to_owned(),
+                        _ => Path::new(filename)
+                            .file_stem()
+                            .unwrap()
+                            .to_string_lossy()
+                            .into_owned(),
+                    };
+                    convert::SampleRegistry::in_sd_root(root, &font_name)
+                }
+                None => convert::SampleRegistry::new(Path::new(
+                    matches.value_of("SAMPLES").unwrap_or("SAMPLES"),
+                )),
+            };
             for ix in 0..(sf.presets.len() - 1) {
-                convert::save_as_xml(&sf, Path::new(xml_folder), Path::new(samples), ix, prefix);
+                convert::save_as_xml(
+                    &sf,
+                    &xml_folder,
+                    &kits,
+                    &mut sample_registry,
+                    ix,
+                    prefix,
+                    &options,
+                );
             }
             // convert::save_as_xml(&sf, Path::new(xml_folder), Path::new(samples), 2, prefix);
             // convert::save_as_xml(&sf, Path::new(xml_folder), Path::new(samples), 5, prefix);
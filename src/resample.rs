@@ -0,0 +1,98 @@
+// Band-limited sample rate conversion with a windowed sinc filter
+use std::f64::consts::PI;
+
+/// The sample rates the deluge can play
+pub const DELUGE_RATES: std::ops::RangeInclusive<u32> = 400..=50000;
+
+/// Zero crossings of the sinc on each side of a sample point
+const ZERO_CROSSINGS: f64 = 16.0;
+
+/// Converts a position in a sample at one rate to the same position at another
+pub fn position(pos: u32, from: u32, to: u32) -> u32 {
+    if from == 0 {
+        return pos;
+    }
+    (pos as f64 * to as f64 / from as f64).round() as u32
+}
+
+/// Resamples the data from one rate to another. The filter cuts off just below the lower of the
+/// two Nyquist frequencies, so nothing aliases when going down in rate.
+pub fn resample(data: &[i32], from: u32, to: u32) -> Vec<i32> {
+    if from == to || from == 0 || data.is_empty() {
+        return data.to_vec();
+    }
+    let ratio = to as f64 / from as f64;
+    let len = position(data.len() as u32, from, to) as usize;
+    // The cutoff relative to the Nyquist frequency of the input
+    let cutoff = ratio.min(1.0) * 0.95;
+    // The half width of the filter in input samples
+    let half_width = ZERO_CROSSINGS / cutoff;
+    let mut out = Vec::with_capacity(len);
+    for ix in 0..len {
+        let center = ix as f64 / ratio;
+        let first = (center - half_width).ceil().max(0.0) as usize;
+        let last = ((center + half_width).floor() as usize).min(data.len() - 1);
+        let mut sum = 0.0;
+        for (j, v) in data.iter().enumerate().take(last + 1).skip(first) {
+            let x = j as f64 - center;
+            sum += *v as f64 * cutoff * sinc(cutoff * x) * blackman(x / half_width);
+        }
+        out.push(sum.round() as i32);
+    }
+    out
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over -1..1
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        0.0
+    } else {
+        0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, len: usize) -> Vec<i32> {
+        (0..len)
+            .map(|ix| (10000.0 * (2.0 * PI * freq * ix as f64 / rate as f64).sin()).round() as i32)
+            .collect()
+    }
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(resample(&[1, 2, 3], 44100, 44100), vec![1, 2, 3]);
+        assert_eq!(position(22050, 22050, 44100), 44100);
+        // A tone well below both Nyquist frequencies comes out the same
+        let up = resample(&sine(1000.0, 22050, 2205), 22050, 44100);
+        assert_eq!(up.len(), 4410);
+        let expected = sine(1000.0, 44100, 4410);
+        for ix in 100..4300 {
+            assert!((up[ix] - expected[ix]).abs() < 50, "{} at {}", up[ix], ix);
+        }
+        let down = resample(&sine(1000.0, 96000, 9600), 96000, 48000);
+        assert_eq!(down.len(), 4800);
+        let expected = sine(1000.0, 48000, 4800);
+        for ix in 100..4700 {
+            assert!(
+                (down[ix] - expected[ix]).abs() < 50,
+                "{} at {}",
+                down[ix],
+                ix
+            );
+        }
+        // A tone above the new Nyquist frequency is filtered out instead of aliasing
+        let filtered = resample(&sine(30000.0, 96000, 9600), 96000, 48000);
+        assert!(filtered[100..4700].iter().all(|v| v.abs() < 50));
+    }
+}
@@ -1,12 +1,93 @@
+use crate::resample;
 use crate::wav;
 use binread::*;
 use log::{debug, error, info, warn};
 use std::collections::VecDeque;
 use std::fmt;
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 
+#[derive(Debug)]
+pub enum SoundFontError {
+    Io(std::io::Error),
+    /// One of the chunks every SoundFont must have is missing
+    MissingChunk(&'static str),
+    /// A chunk claims to be larger than its parent
+    TruncatedChunk {
+        chunk: String,
+        len: u64,
+        available: u64,
+    },
+    /// A chunk doesn't hold a whole number of records
+    TruncatedRecord {
+        chunk: &'static str,
+        len: usize,
+        record_size: usize,
+    },
+    /// An index points outside the table it refers to
+    IndexOutOfRange {
+        table: &'static str,
+        index: usize,
+        len: usize,
+    },
+    /// A compressed (SF3) sample could not be decoded
+    Vorbis {
+        sample: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for SoundFontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoundFontError::Io(e) => write!(f, "I/O error: {}", e),
+            SoundFontError::MissingChunk(chunk) => write!(f, "missing {} chunk", chunk),
+            SoundFontError::TruncatedChunk {
+                chunk,
+                len,
+                available,
+            } => write!(
+                f,
+                "{} chunk is {} bytes, but only {} are available",
+                chunk, len, available
+            ),
+            SoundFontError::TruncatedRecord {
+                chunk,
+                len,
+                record_size,
+            } => write!(
+                f,
+                "{} chunk is {} bytes, which is not a multiple of the record size {}",
+                chunk, len, record_size
+            ),
+            SoundFontError::IndexOutOfRange { table, index, len } => write!(
+                f,
+                "index {} is out of range for {} with {} entries",
+                index, table, len
+            ),
+            SoundFontError::Vorbis { sample, error } => {
+                write!(f, "could not decode sample {}: {}", sample, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SoundFontError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SoundFontError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SoundFontError {
+    fn from(e: std::io::Error) -> Self {
+        SoundFontError::Io(e)
+    }
+}
+
 fn make_string(s: &[u8; 20]) -> String {
     let first_null = s.iter().position(|&x| x == 0).unwrap_or(20);
     std::str::from_utf8(&s[0..first_null])
@@ -30,21 +111,17 @@ pub struct Sample {
     pub sample_type: u16,
 }
 
+const SAMPLE_TYPE_MONO: u16 = 1;
+const SAMPLE_TYPE_RIGHT: u16 = 2;
+const SAMPLE_TYPE_LEFT: u16 = 4;
+// Sample type flag for Ogg Vorbis compressed samples in SF3 files
+const SAMPLE_TYPE_VORBIS: u16 = 0x10;
+// The spec requires 46 zero valued data points after each sample
+const SAMPLE_PADDING: usize = 46;
+
 impl Sample {
-    /// Returns a scaling factor for sample rate that brings the sample into the supported sample rate zone (400-50000)
-    pub fn scale(&self) -> (u32, u32) {
-        let mut numerator = 1;
-        let mut denominator = 1;
-        loop {
-            if numerator * self.sample_rate / denominator < 400 {
-                numerator += 1;
-            } else if numerator * self.sample_rate / denominator > 50000 {
-                denominator += 1;
-            } else {
-                break;
-            }
-        }
-        (numerator, denominator)
+    pub fn is_compressed(&self) -> bool {
+        self.sample_type & SAMPLE_TYPE_VORBIS != 0
     }
 }
 
@@ -192,7 +269,9 @@ impl Generator {
             | Generator::ReleaseVolEnv(x)
             | Generator::ReleaseModEnv(x)
             | Generator::HoldVolEnv(x)
-            | Generator::HoldModEnv(x) => Some(Unit::Seconds(from_cents(x, 1.0))),
+            | Generator::HoldModEnv(x)
+            | Generator::DelayVolEnv(x)
+            | Generator::DelayModEnv(x) => Some(Unit::Seconds(from_cents(x, 1.0))),
             Generator::ChorusEffectsSend(x) | Generator::ReverbEffectsSend(x) => {
                 Some(Unit::Send(percent_from_promille(x, 0.0)))
             }
@@ -204,6 +283,130 @@ impl Generator {
             _ => None,
         }
     }
+
+    /// The generator operator number used in the file
+    pub fn oper(&self) -> Option<u16> {
+        match *self {
+            Generator::StartAddrsOffset(_) => Some(0),
+            Generator::EndAddrsOffset(_) => Some(1),
+            Generator::StartloopAddrsOffset(_) => Some(2),
+            Generator::EndloopAddrsOffset(_) => Some(3),
+            Generator::StartAddrsCoarseOffset(_) => Some(4),
+            Generator::ModLfoToPitch(_) => Some(5),
+            Generator::VibLfoToPitch(_) => Some(6),
+            Generator::ModEnvToPitch(_) => Some(7),
+            Generator::InitialFilterFc(_) => Some(8),
+            Generator::InitialFilterQ(_) => Some(9),
+            Generator::ModLfoToFilterFc(_) => Some(10),
+            Generator::ModEnvToFilterFc(_) => Some(11),
+            Generator::EndAddrsCoarseOffset(_) => Some(12),
+            Generator::ModLfoToVolume(_) => Some(13),
+            Generator::ChorusEffectsSend(_) => Some(15),
+            Generator::ReverbEffectsSend(_) => Some(16),
+            Generator::Pan(_) => Some(17),
+            Generator::DelayModLFO(_) => Some(21),
+            Generator::FreqModLFO(_) => Some(22),
+            Generator::DelayVibLFO(_) => Some(23),
+            Generator::FreqVibLFO(_) => Some(24),
+            Generator::DelayModEnv(_) => Some(25),
+            Generator::AttackModEnv(_) => Some(26),
+            Generator::HoldModEnv(_) => Some(27),
+            Generator::DecayModEnv(_) => Some(28),
+            Generator::SustainModEnv(_) => Some(29),
+            Generator::ReleaseModEnv(_) => Some(30),
+            Generator::KeynumToModEnvHold(_) => Some(31),
+            Generator::KeynumToModEnvDecay(_) => Some(32),
+            Generator::DelayVolEnv(_) => Some(33),
+            Generator::AttackVolEnv(_) => Some(34),
+            Generator::HoldVolEnv(_) => Some(35),
+            Generator::DecayVolEnv(_) => Some(36),
+            Generator::SustainVolEnv(_) => Some(37),
+            Generator::ReleaseVolEnv(_) => Some(38),
+            Generator::KeynumToVolEnvHold(_) => Some(39),
+            Generator::KeynumToVolEnvDecay(_) => Some(40),
+            Generator::Instrument(_) => Some(41),
+            Generator::KeyRange(_, _) => Some(43),
+            Generator::VelRange(_, _) => Some(44),
+            Generator::StartloopAddrsCoarseOffset(_) => Some(45),
+            Generator::Keynum(_) => Some(46),
+            Generator::Velocity(_) => Some(47),
+            Generator::InitialAttenuation(_) => Some(48),
+            Generator::EndloopAddrsCoarseOffset(_) => Some(50),
+            Generator::CoarseTune(_) => Some(51),
+            Generator::FineTune(_) => Some(52),
+            Generator::SampleID(_) => Some(53),
+            Generator::SampleModes(_) => Some(54),
+            Generator::ScaleTuning(_) => Some(56),
+            Generator::ExclusiveClass(_) => Some(57),
+            Generator::OverridingRootKey(_) => Some(58),
+            Generator::EndOper => Some(60),
+            Generator::Unused => None,
+        }
+    }
+
+    /// The raw amount, in the same format as in the file
+    pub fn amount(&self) -> [u8; 2] {
+        match *self {
+            Generator::KeyRange(low, high) | Generator::VelRange(low, high) => [low, high],
+            Generator::Instrument(x) | Generator::SampleID(x) => x.to_ne_bytes(),
+            Generator::SampleModes(mode) => [
+                match mode {
+                    LoopMode::NoLoop => 0,
+                    LoopMode::ContinuousLoop => 1,
+                    LoopMode::ReleaseLoop => 3,
+                },
+                0,
+            ],
+            Generator::EndOper | Generator::Unused => [0, 0],
+            Generator::StartAddrsOffset(x)
+            | Generator::EndAddrsOffset(x)
+            | Generator::StartloopAddrsOffset(x)
+            | Generator::EndloopAddrsOffset(x)
+            | Generator::StartAddrsCoarseOffset(x)
+            | Generator::ModLfoToPitch(x)
+            | Generator::VibLfoToPitch(x)
+            | Generator::ModEnvToPitch(x)
+            | Generator::InitialFilterFc(x)
+            | Generator::InitialFilterQ(x)
+            | Generator::ModLfoToFilterFc(x)
+            | Generator::ModEnvToFilterFc(x)
+            | Generator::EndAddrsCoarseOffset(x)
+            | Generator::ModLfoToVolume(x)
+            | Generator::ChorusEffectsSend(x)
+            | Generator::ReverbEffectsSend(x)
+            | Generator::Pan(x)
+            | Generator::DelayModLFO(x)
+            | Generator::FreqModLFO(x)
+            | Generator::DelayVibLFO(x)
+            | Generator::FreqVibLFO(x)
+            | Generator::DelayModEnv(x)
+            | Generator::AttackModEnv(x)
+            | Generator::HoldModEnv(x)
+            | Generator::DecayModEnv(x)
+            | Generator::SustainModEnv(x)
+            | Generator::ReleaseModEnv(x)
+            | Generator::KeynumToModEnvHold(x)
+            | Generator::KeynumToModEnvDecay(x)
+            | Generator::DelayVolEnv(x)
+            | Generator::AttackVolEnv(x)
+            | Generator::HoldVolEnv(x)
+            | Generator::DecayVolEnv(x)
+            | Generator::SustainVolEnv(x)
+            | Generator::ReleaseVolEnv(x)
+            | Generator::KeynumToVolEnvHold(x)
+            | Generator::KeynumToVolEnvDecay(x)
+            | Generator::StartloopAddrsCoarseOffset(x)
+            | Generator::Keynum(x)
+            | Generator::Velocity(x)
+            | Generator::InitialAttenuation(x)
+            | Generator::EndloopAddrsCoarseOffset(x)
+            | Generator::CoarseTune(x)
+            | Generator::FineTune(x)
+            | Generator::ScaleTuning(x)
+            | Generator::ExclusiveClass(x)
+            | Generator::OverridingRootKey(x) => x.to_ne_bytes(),
+        }
+    }
 }
 
 impl fmt::Display for Generator {
@@ -282,7 +485,7 @@ fn parse_generator(v: u16, a: [u8; 2]) -> Generator {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SourceEnumeratorController {
     NoController,
     NoteOnVelocity,
@@ -293,35 +496,46 @@ pub enum SourceEnumeratorController {
     PitchWheelSensitivity,
     Link,
     MidiCC(u8),
+    Unknown(u8),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SourceType {
     Linear,
     Concave,
     Convex,
     Switch,
+    Unknown(u8),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SourceDirection {
     Forward,
     Reverse,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SourcePolarity {
     Unipolar,
     Bipolar,
 }
 
-#[derive(BinRead, Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ModularTransform {
     Linear,
     AbsoluteValue,
+    Unknown(u16),
 }
 
-#[derive(Debug)]
+fn parse_transform(v: u16) -> ModularTransform {
+    match v {
+        0 => ModularTransform::Linear,
+        2 => ModularTransform::AbsoluteValue,
+        x => ModularTransform::Unknown(x),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Modulator {
     pub continuity: SourceType,
     pub polarity: SourcePolarity,
@@ -335,7 +549,7 @@ fn parse_modulator(v: u16) -> Modulator {
         1 => SourceType::Concave,
         2 => SourceType::Convex,
         3 => SourceType::Switch,
-        _ => panic!(),
+        x => SourceType::Unknown(x as u8),
     };
     let polarity = if (v & 0x200) == 0x200 {
         SourcePolarity::Bipolar
@@ -359,7 +573,7 @@ fn parse_modulator(v: u16) -> Modulator {
             14 => SourceEnumeratorController::PitchWheel,
             16 => SourceEnumeratorController::PitchWheelSensitivity,
             127 => SourceEnumeratorController::Link,
-            _ => panic!(),
+            x => SourceEnumeratorController::Unknown(x as u8),
         }
     };
     Modulator {
@@ -370,21 +584,25 @@ fn parse_modulator(v: u16) -> Modulator {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DestOper {
     Link(u16),
     Generator(Generator),
+    Unknown(u16),
 }
 
 fn parse_dest_oper(v: u16) -> DestOper {
     if (v & 0x8000) == 0x8000 {
         DestOper::Link(v & 0x7ff)
     } else {
-        DestOper::Generator(parse_generator(v, [0, 0]))
+        match parse_generator(v, [0, 0]) {
+            Generator::Unused => DestOper::Unknown(v),
+            g => DestOper::Generator(g),
+        }
     }
 }
 
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone)]
 pub struct ModList {
     #[br(map = |x: u16| parse_modulator(x))]
     pub src_oper: Modulator,
@@ -393,22 +611,347 @@ pub struct ModList {
     pub amount: i16,
     #[br(map = |x: u16| parse_modulator(x))]
     pub amt_src_oper: Modulator,
-    #[br(pad_size_to = 2)]
+    #[br(map = |x: u16| parse_transform(x))]
     pub trans_oper: ModularTransform,
 }
 
+impl ModList {
+    fn new(
+        src_oper: u16,
+        dest_oper: u16,
+        amount: i16,
+        amt_src_oper: u16,
+        trans_oper: u16,
+    ) -> ModList {
+        ModList {
+            src_oper: parse_modulator(src_oper),
+            dest_oper: parse_dest_oper(dest_oper),
+            amount,
+            amt_src_oper: parse_modulator(amt_src_oper),
+            trans_oper: parse_transform(trans_oper),
+        }
+    }
+
+    /// Modulators are the same if everything but the amount is
+    pub fn is_identical(&self, other: &ModList) -> bool {
+        self.src_oper == other.src_oper
+            && self.dest_oper == other.dest_oper
+            && self.amt_src_oper == other.amt_src_oper
+            && self.trans_oper == other.trans_oper
+    }
+
+    /// The modulators every instrument zone has, from the SoundFont 2.01 spec, section 8.4
+    pub fn defaults() -> Vec<ModList> {
+        vec![
+            // Velocity to attenuation and filter cutoff
+            ModList::new(0x0502, 48, 960, 0, 0),
+            ModList::new(0x0102, 8, -2400, 0, 0),
+            // Channel pressure and mod wheel to vibrato depth
+            ModList::new(0x000D, 6, 50, 0, 0),
+            ModList::new(0x0081, 6, 50, 0, 0),
+            // Volume, pan and expression
+            ModList::new(0x0587, 48, 960, 0, 0),
+            ModList::new(0x028A, 17, 1000, 0, 0),
+            ModList::new(0x058B, 48, 960, 0, 0),
+            // Reverb and chorus sends
+            ModList::new(0x00DB, 16, 200, 0, 0),
+            ModList::new(0x00DD, 15, 200, 0, 0),
+            // Pitch wheel, scaled by the pitch wheel sensitivity
+            ModList::new(0x020E, 52, 12700, 0x0010, 0),
+        ]
+    }
+}
+
+/// Modulators of a zone replace identical ones from the global zone or the defaults
+fn override_modulators(modulators: &mut Vec<ModList>, zone: &[ModList]) {
+    for m in zone {
+        match modulators.iter_mut().find(|x| x.is_identical(m)) {
+            Some(x) => x.amount = m.amount,
+            None => modulators.push(m.clone()),
+        }
+    }
+}
+
 #[derive(BinRead, Debug)]
 pub struct Bag {
     pub gen_ndx: u16,
     pub mod_ndx: u16,
 }
 
-#[derive(BinRead, Debug)]
+/// A sample together with all the generators that apply to it when played from a preset.
+/// Instrument and preset zones are resolved as described in the SoundFont 2.04 spec, section 9.4.
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// The preset zone the region comes from, i.e. which layer of the preset it belongs to
+    pub preset_zone: usize,
+    pub instrument: usize,
+    pub sample: usize,
+    pub key_range: (u8, u8),
+    pub vel_range: (u8, u8),
+    /// One generator for each operator, with the default value if it wasn't set
+    pub generators: Vec<Generator>,
+    /// The instrument modulators, including the default ones, with the preset modulators added
+    pub modulators: Vec<ModList>,
+}
+
+/// How sample data is written to a wav file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WavOptions {
+    pub loop_mode: LoopMode,
+    /// The sample data is scaled by this
+    pub gain: f32,
+    /// The sample data is resampled to this rate
+    pub sample_rate: u32,
+}
+
+/// Sample point positions in the sample data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleWindow {
+    pub start: u32,
+    pub end: u32,
+    pub start_loop: u32,
+    pub end_loop: u32,
+}
+
+impl SampleWindow {
+    /// The window of the sample header
+    pub fn of(sample: &Sample) -> SampleWindow {
+        SampleWindow {
+            start: sample.start,
+            end: sample.end,
+            start_loop: sample.start_loop,
+            end_loop: sample.end_loop,
+        }
+    }
+
+    /// Moves the window from one sample to the same place in another, e.g. the other half of a stereo pair
+    pub fn rebase(&self, from: &Sample, to: &Sample) -> SampleWindow {
+        // Clamped to the other sample, so a shorter one isn't read past its end
+        let shift = |x: u32| {
+            (x as i64 - from.start as i64 + to.start as i64).clamp(to.start as i64, to.end as i64)
+                as u32
+        };
+        SampleWindow {
+            start: shift(self.start),
+            end: shift(self.end),
+            start_loop: shift(self.start_loop),
+            end_loop: shift(self.end_loop),
+        }
+    }
+}
+
+impl Region {
+    /// The part of the sample the region plays, with the address offset generators applied
+    pub fn sample_window(&self, sample: &Sample) -> SampleWindow {
+        let mut offsets = [0i64; 4];
+        for g in &self.generators {
+            match *g {
+                Generator::StartAddrsOffset(x) => offsets[0] += x as i64,
+                Generator::StartAddrsCoarseOffset(x) => offsets[0] += 32768 * x as i64,
+                Generator::EndAddrsOffset(x) => offsets[1] += x as i64,
+                Generator::EndAddrsCoarseOffset(x) => offsets[1] += 32768 * x as i64,
+                Generator::StartloopAddrsOffset(x) => offsets[2] += x as i64,
+                Generator::StartloopAddrsCoarseOffset(x) => offsets[2] += 32768 * x as i64,
+                Generator::EndloopAddrsOffset(x) => offsets[3] += x as i64,
+                Generator::EndloopAddrsCoarseOffset(x) => offsets[3] += 32768 * x as i64,
+                _ => {}
+            }
+        }
+        let apply = |x: u32, offset: i64| (x as i64 + offset).clamp(0, u32::MAX as i64) as u32;
+        let start = apply(sample.start, offsets[0]);
+        let end = apply(sample.end, offsets[1]).max(start);
+        SampleWindow {
+            start,
+            end,
+            start_loop: apply(sample.start_loop, offsets[2]).clamp(start, end),
+            end_loop: apply(sample.end_loop, offsets[3]).clamp(start, end),
+        }
+    }
+}
+
+const NUM_GENERATORS: usize = 61;
+const GEN_INSTRUMENT: usize = 41;
+const GEN_KEY_RANGE: usize = 43;
+const GEN_VEL_RANGE: usize = 44;
+const GEN_SAMPLE_ID: usize = 53;
+
+type Amounts = [Option<[u8; 2]>; NUM_GENERATORS];
+
+fn default_amount(oper: usize) -> [u8; 2] {
+    let v: i16 = match oper {
+        8 => 13500,
+        21 | 23 | 25 | 26 | 27 | 28 | 30 | 33 | 34 | 35 | 36 | 38 => -12000,
+        GEN_KEY_RANGE | GEN_VEL_RANGE => return [0, 127],
+        46 | 47 | 58 => -1,
+        56 => 100,
+        _ => 0,
+    };
+    v.to_ne_bytes()
+}
+
+/// Generators that are only valid in instrument zones, and ignored in preset zones
+fn is_instrument_only(oper: usize) -> bool {
+    matches!(oper, 0..=4 | 12 | 45..=47 | 50 | 54 | 57 | 58)
+}
+
+/// Applies the generators of a zone on top of `amounts`, later generators win
+fn set_amounts(amounts: &mut Amounts, zone: &[Generator]) {
+    for g in zone {
+        if let Some(oper) = g.oper() {
+            if let Some(amount) = amounts.get_mut(oper as usize) {
+                *amount = Some(g.amount());
+            }
+        }
+    }
+}
+
+fn intersect(a: [u8; 2], b: [u8; 2]) -> Option<(u8, u8)> {
+    let low = a[0].max(b[0]);
+    let high = a[1].min(b[1]);
+    if low <= high {
+        Some((low, high))
+    } else {
+        None
+    }
+}
+
+/// A preset or instrument zone
+#[derive(Debug, Clone, Copy)]
+pub struct Zone<'a> {
+    pub generators: &'a [Generator],
+    pub modulators: &'a [ModList],
+}
+
+/// The zones of a preset or an instrument
+#[derive(Debug, Clone)]
+pub struct Zones<'a> {
+    /// Generators and modulators that apply to all the other zones, unless they override them
+    pub global: Option<Zone<'a>>,
+    pub zones: Vec<Zone<'a>>,
+}
+
+impl<'a> Zones<'a> {
+    /// Only the first zone can be global, and is so if it lacks the terminal generator.
+    /// Any other zones without the terminal generator are ignored.
+    fn new(zones: Vec<Zone<'a>>, terminal: usize) -> Zones<'a> {
+        let is_terminated = |zone: &Zone| {
+            zone.generators
+                .last()
+                .and_then(|g| g.oper())
+                .is_some_and(|oper| oper as usize == terminal)
+        };
+        let mut global = None;
+        let mut rest = vec![];
+        for (ix, zone) in zones.into_iter().enumerate() {
+            if is_terminated(&zone) {
+                rest.push(zone);
+            } else if ix == 0 {
+                global = Some(zone);
+            } else {
+                warn!("Ignoring zone without {} generator", terminal);
+            }
+        }
+        Zones {
+            global,
+            zones: rest,
+        }
+    }
+}
+
+fn zone_generators<'a>(bags: &[Bag], gens: &'a [Generator], bag_ix: usize) -> &'a [Generator] {
+    let gen_start = bags[bag_ix].gen_ndx as usize;
+    let gen_end = bags
+        .get(bag_ix + 1)
+        .map_or(gens.len(), |b| b.gen_ndx as usize);
+    gens.get(gen_start..gen_end).unwrap_or(&[])
+}
+
+fn zone_modulators<'a>(bags: &[Bag], mods: &'a [ModList], bag_ix: usize) -> &'a [ModList] {
+    let mod_start = bags[bag_ix].mod_ndx as usize;
+    let mod_end = bags
+        .get(bag_ix + 1)
+        .map_or(mods.len(), |b| b.mod_ndx as usize);
+    mods.get(mod_start..mod_end).unwrap_or(&[])
+}
+
+#[derive(BinRead, Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Version {
     pub major: u16,
     pub minor: u16,
 }
 
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}", self.major, self.minor)
+    }
+}
+
+/// The contents of the INFO list
+#[derive(Debug, Default, Clone)]
+pub struct SoundFontInfo {
+    /// ifil, the version of the SoundFont spec the file conforms to
+    pub version: Version,
+    /// isng
+    pub sound_engine: Option<String>,
+    /// INAM
+    pub name: Option<String>,
+    /// irom
+    pub rom_name: Option<String>,
+    /// iver
+    pub rom_version: Option<Version>,
+    /// ICRD
+    pub creation_date: Option<String>,
+    /// IENG
+    pub author: Option<String>,
+    /// IPRD
+    pub product: Option<String>,
+    /// ICOP
+    pub copyright: Option<String>,
+    /// ICMT
+    pub comments: Option<String>,
+    /// ISFT
+    pub creation_tool: Option<String>,
+}
+
+impl SoundFontInfo {
+    /// Best effort check for copyright notices that don't allow the samples to be passed on
+    pub fn forbids_redistribution(&self) -> bool {
+        const PHRASES: [&str; 8] = [
+            "not for redistribution",
+            "no redistribution",
+            "not be redistributed",
+            "not redistribute",
+            "not be distributed",
+            "not distribute",
+            "redistribution is prohibited",
+            "redistribution prohibited",
+        ];
+        if let Some(copyright) = &self.copyright {
+            let copyright = copyright.to_lowercase().replace("n't", " not");
+            PHRASES.iter().any(|p| copyright.contains(p))
+        } else {
+            false
+        }
+    }
+}
+
+/// INFO strings are zero terminated, and not always valid utf-8
+fn make_info_string(data: &[u8]) -> String {
+    let first_null = data.iter().position(|&x| x == 0).unwrap_or(data.len());
+    let data = &data[0..first_null];
+    match std::str::from_utf8(data) {
+        Ok(s) => s.trim().to_string(),
+        Err(_) => {
+            debug!("invalid utf-8, assuming latin-1");
+            data.iter()
+                .map(|&x| x as char)
+                .collect::<String>()
+                .trim()
+                .to_string()
+        }
+    }
+}
+
 // #         Name         Unit         Abs         Zero Min         Min         Useful Max        Max        Useful De-fault Def Value
 // 0 startAddrsOffset, +, smpls, 0, 0, None, *, *, 0, None
 // 1 endAddrsOffset, +, smpls, 0              *              *              0              None              0              None
@@ -476,6 +1019,7 @@ const IFIL: [u8; 4] = [b'i', b'f', b'i', b'l'];
 const SDTA: [u8; 4] = [b's', b'd', b't', b'a'];
 const SHDR: [u8; 4] = [b's', b'h', b'd', b'r'];
 const SMPL: [u8; 4] = [b's', b'm', b'p', b'l'];
+const SM24: [u8; 4] = [b's', b'm', b'2', b'4'];
 const PHDR: [u8; 4] = [b'p', b'h', b'd', b'r'];
 const INST: [u8; 4] = [b'i', b'n', b's', b't'];
 const IGEN: [u8; 4] = [b'i', b'g', b'e', b'n'];
@@ -485,9 +1029,110 @@ const PMOD: [u8; 4] = [b'p', b'm', b'o', b'd'];
 const IBAG: [u8; 4] = [b'i', b'b', b'a', b'g'];
 const PBAG: [u8; 4] = [b'p', b'b', b'a', b'g'];
 
+/// Decodes a mono Ogg Vorbis stream to 16 bit PCM
+fn decode_vorbis(data: &[u8]) -> Result<Vec<i16>, String> {
+    let mut reader =
+        lewton::inside_ogg::OggStreamReader::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let channels = reader.ident_hdr.audio_channels as usize;
+    if channels != 1 {
+        warn!(
+            "compressed sample has {} channels, using the first",
+            channels
+        );
+    }
+    let mut out = vec![];
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|e| e.to_string())? {
+        out.extend(packet.into_iter().step_by(channels.max(1)));
+    }
+    Ok(out)
+}
+
+/// Reads the sub chunks of a RIFF or LIST chunk
+fn read_children<R: Read + Seek>(
+    chunk: &riff::Chunk,
+    file: &mut R,
+) -> Result<Vec<riff::Chunk>, SoundFontError> {
+    let end = chunk.offset() + 8 + chunk.len() as u64;
+    // Skip the header and the list type
+    let mut pos = chunk.offset() + 12;
+    let mut children = vec![];
+    while pos + 8 <= end {
+        let child = riff::Chunk::read(file, pos)?;
+        check_chunk_size(&child, end)?;
+        let len = child.len() as u64;
+        pos += 8 + len + (len % 2);
+        children.push(child);
+    }
+    Ok(children)
+}
+
+fn check_chunk_size(chunk: &riff::Chunk, end: u64) -> Result<(), SoundFontError> {
+    let available = end.saturating_sub(chunk.offset() + 8);
+    if chunk.len() as u64 > available {
+        return Err(SoundFontError::TruncatedChunk {
+            chunk: chunk.id().to_string(),
+            len: chunk.len() as u64,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Reads a chunk that consists of fixed size records
+fn read_records<T: BinRead<Args = ()>, R: Read + Seek>(
+    chunk: &riff::Chunk,
+    file: &mut R,
+    name: &'static str,
+    record_size: usize,
+) -> Result<Vec<T>, SoundFontError> {
+    let data = chunk.read_contents(file)?;
+    if data.len() % record_size != 0 {
+        return Err(SoundFontError::TruncatedRecord {
+            chunk: name,
+            len: data.len(),
+            record_size,
+        });
+    }
+    let num = data.len() / record_size;
+    let mut reader = Cursor::new(data);
+    let mut records = Vec::with_capacity(num);
+    for _ in 0..num {
+        let record = reader.read_ne::<T>().map_err(|e| match e {
+            binread::Error::Io(e) => SoundFontError::Io(e),
+            _ => SoundFontError::TruncatedRecord {
+                chunk: name,
+                len: num * record_size,
+                record_size,
+            },
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn check_index<I: Iterator<Item = u16>>(
+    table: &'static str,
+    indices: I,
+    len: usize,
+) -> Result<(), SoundFontError> {
+    for index in indices {
+        if index as usize >= len {
+            return Err(SoundFontError::IndexOutOfRange {
+                table,
+                index: index as usize,
+                len,
+            });
+        }
+    }
+    Ok(())
+}
+
 pub struct SoundFont {
+    pub info: SoundFontInfo,
     pub samples: Vec<Sample>,
     pub sample_data: Vec<u8>,
+    /// The least significant byte of each sample in 24 bit fonts
+    pub sample_data_24: Option<Vec<u8>>,
     pub presets: Vec<Preset>,
     pub instruments: Vec<Instrument>,
     pub igens: Vec<Generator>,
@@ -499,14 +1144,18 @@ pub struct SoundFont {
 }
 
 impl SoundFont {
-    pub fn parse_soundfont(file: &mut fs::File) -> SoundFont {
-        let chunk = riff::Chunk::read(file, 0).unwrap();
+    pub fn parse_soundfont<R: Read + Seek>(file: &mut R) -> Result<SoundFont, SoundFontError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let chunk = riff::Chunk::read(file, 0)?;
+        check_chunk_size(&chunk, file_len)?;
         let mut todo = VecDeque::new();
         todo.push_back((chunk, 1));
-        let mut samples = vec![];
-        let mut sample_data = vec![];
-        let mut presets = vec![];
-        let mut instruments = vec![];
+        let mut samples = None;
+        let mut sample_data = None;
+        let mut sample_data_24 = None;
+        let mut info = SoundFontInfo::default();
+        let mut presets = None;
+        let mut instruments = None;
         let mut igens = vec![];
         let mut pgens = vec![];
         let mut imods = vec![];
@@ -523,12 +1172,12 @@ impl SoundFont {
             );
             match c.id().value {
                 RIFF | LIST | SDTA => {
-                    for child in c.iter(file) {
+                    for child in read_children(&c, file)? {
                         todo.push_back((child, indent + 1));
                     }
                 }
                 IFIL | IVER => {
-                    let data = c.read_contents(file).unwrap();
+                    let data = c.read_contents(file)?;
                     let mut reader = Cursor::new(data);
                     if let Ok(version) = reader.read_ne::<Version>() {
                         debug!(
@@ -538,23 +1187,37 @@ impl SoundFont {
                             indent = 2 * (indent + 1),
                             chr = ' '
                         );
+                        if c.id().value == IFIL {
+                            info.version = version;
+                        } else {
+                            info.rom_version = Some(version);
+                        }
                     }
                 }
                 INAM | ISFT | IENG | ICOP | ISNG | IROM | ICRD | IPRD | ICMT => {
-                    let data = c.read_contents(file).unwrap();
-                    if let Ok(name) = String::from_utf8(data) {
-                        debug!(
-                            "{chr:>indent$}Name: {}",
-                            name,
-                            indent = 2 * (indent + 1),
-                            chr = ' '
-                        );
-                    } else {
-                        warn!("invalid utf-8!");
-                    }
+                    let data = c.read_contents(file)?;
+                    let name = make_info_string(&data);
+                    debug!(
+                        "{chr:>indent$}Name: {}",
+                        name,
+                        indent = 2 * (indent + 1),
+                        chr = ' '
+                    );
+                    let field = match c.id().value {
+                        INAM => &mut info.name,
+                        ISFT => &mut info.creation_tool,
+                        IENG => &mut info.author,
+                        ICOP => &mut info.copyright,
+                        ISNG => &mut info.sound_engine,
+                        IROM => &mut info.rom_name,
+                        ICRD => &mut info.creation_date,
+                        IPRD => &mut info.product,
+                        _ => &mut info.comments,
+                    };
+                    *field = Some(name);
                 }
                 SMPL => {
-                    sample_data = c.read_contents(file).unwrap();
+                    sample_data = Some(c.read_contents(file)?);
                     debug!(
                         "{chr:>indent$}Samples: {}",
                         c.len() / 2,
@@ -562,10 +1225,18 @@ impl SoundFont {
                         chr = ' '
                     );
                 }
+                SM24 => {
+                    sample_data_24 = Some(c.read_contents(file)?);
+                    debug!(
+                        "{chr:>indent$}24 bit samples: {}",
+                        c.len(),
+                        indent = 2 * (indent + 1),
+                        chr = ' '
+                    );
+                }
                 SHDR => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(sample) = reader.read_ne::<Sample>() {
+                    let records = read_records::<Sample, _>(&c, file, "shdr", 46)?;
+                    for sample in &records {
                         if !sample.name.starts_with("EOS") {
                             debug!(
                                 "{chr:>indent$}Sample: {}",
@@ -574,13 +1245,12 @@ impl SoundFont {
                                 chr = ' '
                             );
                         }
-                        samples.push(sample);
                     }
+                    samples = Some(records);
                 }
                 PHDR => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(preset) = reader.read_ne::<Preset>() {
+                    let records = read_records::<Preset, _>(&c, file, "phdr", 38)?;
+                    for preset in &records {
                         if !preset.name.starts_with("EOP") {
                             debug!(
                                 "{chr:>indent$}Preset: {}",
@@ -589,13 +1259,12 @@ impl SoundFont {
                                 chr = ' '
                             );
                         }
-                        presets.push(preset);
                     }
+                    presets = Some(records);
                 }
                 INST => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(instrument) = reader.read_ne::<Instrument>() {
+                    let records = read_records::<Instrument, _>(&c, file, "inst", 22)?;
+                    for instrument in &records {
                         if !instrument.name.starts_with("EOI") {
                             debug!(
                                 "{chr:>indent$}Instrument: {}",
@@ -604,13 +1273,11 @@ impl SoundFont {
                                 chr = ' '
                             );
                         }
-                        instruments.push(instrument);
                     }
+                    instruments = Some(records);
                 }
                 IGEN => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(generator) = reader.read_ne::<GeneratorData>() {
+                    for generator in read_records::<GeneratorData, _>(&c, file, "igen", 4)? {
                         debug!(
                             "{chr:>indent$}Instrument Generator: {:?}, {:?}",
                             generator.oper,
@@ -622,9 +1289,7 @@ impl SoundFont {
                     }
                 }
                 PGEN => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(generator) = reader.read_ne::<GeneratorData>() {
+                    for generator in read_records::<GeneratorData, _>(&c, file, "pgen", 4)? {
                         debug!(
                             "{chr:>indent$}Instrument Generator: {:?}, {:?}",
                             generator.oper,
@@ -636,9 +1301,7 @@ impl SoundFont {
                     }
                 }
                 IMOD => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(mod_list) = reader.read_ne::<ModList>() {
+                    for mod_list in read_records::<ModList, _>(&c, file, "imod", 10)? {
                         debug!(
                             "{chr:>indent$}Instrument ModList: {:?}",
                             mod_list,
@@ -649,9 +1312,7 @@ impl SoundFont {
                     }
                 }
                 PMOD => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(mod_list) = reader.read_ne::<ModList>() {
+                    for mod_list in read_records::<ModList, _>(&c, file, "pmod", 10)? {
                         debug!(
                             "{chr:>indent$}Preset ModList: {:?}",
                             mod_list,
@@ -662,9 +1323,7 @@ impl SoundFont {
                     }
                 }
                 IBAG => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(bag) = reader.read_ne::<Bag>() {
+                    for bag in read_records::<Bag, _>(&c, file, "ibag", 4)? {
                         debug!(
                             "{chr:>indent$}Instrument Bag: {:?}",
                             bag,
@@ -675,9 +1334,7 @@ impl SoundFont {
                     }
                 }
                 PBAG => {
-                    let data = c.read_contents(file).unwrap();
-                    let mut reader = Cursor::new(data);
-                    while let Ok(bag) = reader.read_ne::<Bag>() {
+                    for bag in read_records::<Bag, _>(&c, file, "pbag", 4)? {
                         debug!(
                             "{chr:>indent$}Preset Bag: {:?}",
                             bag,
@@ -691,21 +1348,178 @@ impl SoundFont {
             }
         }
 
-        SoundFont {
-            samples,
+        let sample_data = sample_data.ok_or(SoundFontError::MissingChunk("smpl"))?;
+        // sm24 is only valid from version 2.04, and must cover every sample in smpl
+        let sample_data_24 = sample_data_24.filter(|sm24| {
+            let supported = (info.version.major, info.version.minor) >= (2, 4);
+            if !supported || sm24.len() < sample_data.len() / 2 {
+                warn!("Ignoring sm24 chunk");
+                return false;
+            }
+            true
+        });
+        let mut sf = SoundFont {
+            info,
+            samples: samples.ok_or(SoundFontError::MissingChunk("shdr"))?,
             sample_data,
-            presets,
-            instruments,
+            sample_data_24,
+            presets: presets.ok_or(SoundFontError::MissingChunk("phdr"))?,
+            instruments: instruments.ok_or(SoundFontError::MissingChunk("inst"))?,
             igens,
             pgens,
             imods,
             pmods,
             ibags,
             pbags,
+        };
+        if sf.samples.iter().any(|s| s.is_compressed()) {
+            sf.decode_compressed_samples()?;
         }
+        sf.validate()?;
+        Ok(sf)
+    }
+
+    /// Replaces the Ogg Vorbis streams of an SF3 file with PCM data, so that the samples look like they came from an SF2 file
+    fn decode_compressed_samples(&mut self) -> Result<(), SoundFontError> {
+        let mut pcm: Vec<u8> = vec![];
+        let num = self.samples.len().saturating_sub(1);
+        for sample in &mut self.samples[0..num] {
+            // Compressed samples are byte offsets, uncompressed ones are still sample offsets
+            let (start, end) = if sample.is_compressed() {
+                (sample.start as usize, sample.end as usize)
+            } else {
+                (2 * sample.start as usize, 2 * sample.end as usize)
+            };
+            if start > end || end > self.sample_data.len() {
+                return Err(SoundFontError::IndexOutOfRange {
+                    table: "smpl",
+                    index: end,
+                    len: self.sample_data.len(),
+                });
+            }
+            let data = &self.sample_data[start..end];
+            let decoded = if sample.is_compressed() {
+                decode_vorbis(data).map_err(|error| SoundFontError::Vorbis {
+                    sample: sample.name.clone(),
+                    error,
+                })?
+            } else {
+                data.chunks_exact(2)
+                    .map(|x| i16::from_le_bytes([x[0], x[1]]))
+                    .collect()
+            };
+            let offset = (pcm.len() / 2) as u32;
+            if sample.is_compressed() {
+                // Loop points of compressed samples are relative to the sample start
+                sample.start_loop += offset;
+                sample.end_loop += offset;
+            } else {
+                sample.start_loop = sample.start_loop.saturating_sub(sample.start) + offset;
+                sample.end_loop = sample.end_loop.saturating_sub(sample.start) + offset;
+            }
+            sample.start = offset;
+            sample.end = offset + decoded.len() as u32;
+            sample.sample_type &= !SAMPLE_TYPE_VORBIS;
+            debug!("Decoded sample {}: {} samples", sample.name, decoded.len());
+            for x in decoded {
+                pcm.extend(x.to_le_bytes());
+            }
+            pcm.extend([0u8; 2 * SAMPLE_PADDING]);
+        }
+        self.sample_data = pcm;
+        // The low bytes don't match the decoded data
+        self.sample_data_24 = None;
+        Ok(())
+    }
+
+    /// Checks that all indices between the tables are in range, so that later lookups can't panic
+    fn validate(&self) -> Result<(), SoundFontError> {
+        // The last preset, instrument and sample are terminators
+        if self.presets.len() < 2 {
+            return Err(SoundFontError::MissingChunk("phdr"));
+        }
+        check_index(
+            "pbag",
+            self.presets.iter().map(|p| p.bag_index),
+            self.pbags.len(),
+        )?;
+        check_index(
+            "pgen",
+            self.pbags.iter().map(|b| b.gen_ndx),
+            self.pgens.len() + 1,
+        )?;
+        check_index(
+            "pmod",
+            self.pbags.iter().map(|b| b.mod_ndx),
+            self.pmods.len() + 1,
+        )?;
+        check_index(
+            "ibag",
+            self.instruments.iter().map(|i| i.bag_index),
+            self.ibags.len(),
+        )?;
+        check_index(
+            "igen",
+            self.ibags.iter().map(|b| b.gen_ndx),
+            self.igens.len() + 1,
+        )?;
+        check_index(
+            "imod",
+            self.ibags.iter().map(|b| b.mod_ndx),
+            self.imods.len() + 1,
+        )?;
+        check_index(
+            "inst",
+            self.pgens.iter().filter_map(|g| match g {
+                Generator::Instrument(ix) => Some(*ix),
+                _ => None,
+            }),
+            self.instruments.len().saturating_sub(1),
+        )?;
+        check_index(
+            "shdr",
+            self.igens.iter().filter_map(|g| match g {
+                Generator::SampleID(ix) => Some(*ix),
+                _ => None,
+            }),
+            self.samples.len().saturating_sub(1),
+        )?;
+        let num_samples = self.sample_data.len() / 2;
+        for sample in &self.samples[0..self.samples.len().saturating_sub(1)] {
+            // ROM samples don't live in the smpl chunk
+            if sample.sample_type & 0x8000 != 0 {
+                continue;
+            }
+            if sample.start > sample.end || sample.end as usize > num_samples {
+                return Err(SoundFontError::IndexOutOfRange {
+                    table: "smpl",
+                    index: sample.end as usize,
+                    len: num_samples,
+                });
+            }
+        }
+        Ok(())
     }
 
     pub fn dump(&self) {
+        info!("Info:");
+        info!("  Version: {}", self.info.version);
+        let fields = [
+            ("Name", &self.info.name),
+            ("Sound engine", &self.info.sound_engine),
+            ("ROM", &self.info.rom_name),
+            ("Created", &self.info.creation_date),
+            ("Author", &self.info.author),
+            ("Product", &self.info.product),
+            ("Copyright", &self.info.copyright),
+            ("Comments", &self.info.comments),
+            ("Tool", &self.info.creation_tool),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                info!("  {}: {}", name, value);
+            }
+        }
         info!("Presets:");
         for ix in 0..self.presets.len() - 1 {
             self.dump_preset(ix);
@@ -713,32 +1527,21 @@ impl SoundFont {
     }
 
     pub fn dump_preset(&self, ix: usize) {
-        let is_last = ix == self.presets.len() - 1;
         let preset = &self.presets[ix];
         info!("  Name: {}", preset.name);
         info!("  Pos: {}", preset.preset);
         info!("  Bank: {}", preset.bank);
-        let bag_start = preset.bag_index as usize;
-        let bag_end = if is_last {
-            self.pbags.len()
-        } else {
-            let next_preset = &self.presets[ix + 1];
-            next_preset.bag_index as usize
-        };
-        for (zone, bag_ix) in (bag_start..bag_end).enumerate() {
-            info!("  Preset zone {}:", zone + 1);
-            let is_last = ix == self.pbags.len() - 1;
-            let bag = &self.pbags[bag_ix];
-            let gen_start = bag.gen_ndx as usize;
-            let gen_end = if is_last {
-                self.pgens.len()
-            } else {
-                let next_bag = &self.pbags[bag_ix + 1];
-                next_bag.gen_ndx as usize
-            };
+        let zones = self.preset_zones(ix);
+        let global = zones
+            .global
+            .iter()
+            .map(|z| ("Preset global zone".to_string(), z));
+        let local =
+            (zones.zones.iter().enumerate()).map(|(n, z)| (format!("Preset zone {}", n + 1), z));
+        for (title, zone) in global.chain(local) {
+            info!("  {}:", title);
             info!("    Generators:");
-            for gen_ix in gen_start..gen_end {
-                let gen = &self.pgens[gen_ix];
+            for gen in zone.generators {
                 match gen {
                     Generator::Instrument(index) => {
                         self.dump_instrument(*index as usize);
@@ -748,46 +1551,44 @@ impl SoundFont {
                     }
                 }
             }
-            let mod_start = bag.mod_ndx as usize;
-            let mod_end = if is_last {
-                self.pmods.len()
-            } else {
-                let next_bag = &self.pbags[bag_ix + 1];
-                next_bag.mod_ndx as usize
-            };
             info!("    Modulators:");
-            for mod_ix in mod_start..mod_end {
-                info!("      {:?}", self.pmods[mod_ix]);
+            for m in zone.modulators {
+                info!("      {:?}", m);
+            }
+        }
+        info!("  Regions:");
+        for region in self.regions(ix) {
+            info!(
+                "    Sample: {}, keys: {:?}, velocities: {:?}",
+                self.samples[region.sample].name, region.key_range, region.vel_range
+            );
+            for gen in &region.generators {
+                let oper = gen.oper().unwrap_or_default() as usize;
+                if gen.amount() != default_amount(oper)
+                    && oper != GEN_KEY_RANGE
+                    && oper != GEN_VEL_RANGE
+                {
+                    info!("      {}", gen);
+                }
             }
         }
         info!("");
     }
 
     pub fn dump_instrument(&self, ix: usize) {
-        let is_last = ix == self.instruments.len() - 1;
         let instrument = &self.instruments[ix];
         info!("      Instrument: {}", instrument.name);
-        let bag_start = instrument.bag_index as usize;
-        let bag_end = if is_last {
-            self.ibags.len()
-        } else {
-            let next_instrument = &self.instruments[ix + 1];
-            next_instrument.bag_index as usize
-        };
-        for (zone, bag_ix) in (bag_start..bag_end).enumerate() {
-            info!("        Instrument zone {}:", zone + 1);
-            let is_last = ix == self.ibags.len() - 1;
-            let bag = &self.ibags[bag_ix];
-            let gen_start = bag.gen_ndx as usize;
-            let gen_end = if is_last {
-                self.igens.len()
-            } else {
-                let next_bag = &self.ibags[bag_ix + 1];
-                next_bag.gen_ndx as usize
-            };
+        let zones = self.instrument_zones(ix);
+        let global = zones
+            .global
+            .iter()
+            .map(|z| ("Instrument global zone".to_string(), z));
+        let local = (zones.zones.iter().enumerate())
+            .map(|(n, z)| (format!("Instrument zone {}", n + 1), z));
+        for (title, zone) in global.chain(local) {
+            info!("        {}:", title);
             info!("          Generators:");
-            for gen_ix in gen_start..gen_end {
-                let gen = &self.igens[gen_ix];
+            for gen in zone.generators {
                 match gen {
                     Generator::SampleID(index) => {
                         info!("              {:?}", self.samples[*index as usize]);
@@ -797,21 +1598,143 @@ impl SoundFont {
                     }
                 }
             }
-            let mod_start = bag.mod_ndx as usize;
-            let mod_end = if is_last {
-                self.imods.len()
-            } else {
-                let next_bag = &self.ibags[bag_ix + 1];
-                next_bag.mod_ndx as usize
-            };
             info!("          Modulators:");
-            for mod_ix in mod_start..mod_end {
-                info!("             {:?}", self.imods[mod_ix]);
+            for m in zone.modulators {
+                info!("             {:?}", m);
             }
         }
         info!("");
     }
 
+    fn preset_bags(&self, ix: usize) -> std::ops::Range<usize> {
+        let bag_start = self.presets[ix].bag_index as usize;
+        let bag_end = self
+            .presets
+            .get(ix + 1)
+            .map_or(self.pbags.len(), |p| p.bag_index as usize);
+        bag_start..bag_end
+    }
+
+    fn instrument_bags(&self, ix: usize) -> std::ops::Range<usize> {
+        let bag_start = self.instruments[ix].bag_index as usize;
+        let bag_end = self
+            .instruments
+            .get(ix + 1)
+            .map_or(self.ibags.len(), |i| i.bag_index as usize);
+        bag_start..bag_end
+    }
+
+    pub fn preset_zones(&self, ix: usize) -> Zones<'_> {
+        let zones = self
+            .preset_bags(ix)
+            .map(|bag_ix| Zone {
+                generators: zone_generators(&self.pbags, &self.pgens, bag_ix),
+                modulators: zone_modulators(&self.pbags, &self.pmods, bag_ix),
+            })
+            .collect();
+        Zones::new(zones, GEN_INSTRUMENT)
+    }
+
+    pub fn instrument_zones(&self, ix: usize) -> Zones<'_> {
+        let zones = self
+            .instrument_bags(ix)
+            .map(|bag_ix| Zone {
+                generators: zone_generators(&self.ibags, &self.igens, bag_ix),
+                modulators: zone_modulators(&self.ibags, &self.imods, bag_ix),
+            })
+            .collect();
+        Zones::new(zones, GEN_SAMPLE_ID)
+    }
+
+    /// Resolves the preset into the regions that a SoundFont player would use
+    pub fn regions(&self, preset_ix: usize) -> Vec<Region> {
+        let mut regions = vec![];
+        let preset_zones = self.preset_zones(preset_ix);
+        for (preset_zone, pzone) in preset_zones.zones.iter().enumerate() {
+            let mut preset_amounts: Amounts = [None; NUM_GENERATORS];
+            if let Some(global) = &preset_zones.global {
+                set_amounts(&mut preset_amounts, global.generators);
+            }
+            set_amounts(&mut preset_amounts, pzone.generators);
+            let instrument = match preset_amounts[GEN_INSTRUMENT] {
+                Some(amount) => u16::from_ne_bytes(amount) as usize,
+                None => continue,
+            };
+            let mut preset_modulators = vec![];
+            if let Some(global) = &preset_zones.global {
+                override_modulators(&mut preset_modulators, global.modulators);
+            }
+            override_modulators(&mut preset_modulators, pzone.modulators);
+            let inst_zones = self.instrument_zones(instrument);
+            for izone in &inst_zones.zones {
+                let mut amounts: Amounts = [None; NUM_GENERATORS];
+                if let Some(global) = &inst_zones.global {
+                    set_amounts(&mut amounts, global.generators);
+                }
+                set_amounts(&mut amounts, izone.generators);
+                let sample = match amounts[GEN_SAMPLE_ID] {
+                    Some(amount) => u16::from_ne_bytes(amount) as usize,
+                    None => continue,
+                };
+                let range = |oper: usize| {
+                    intersect(
+                        amounts[oper].unwrap_or(default_amount(oper)),
+                        preset_amounts[oper].unwrap_or(default_amount(oper)),
+                    )
+                };
+                let (key_range, vel_range) = match (range(GEN_KEY_RANGE), range(GEN_VEL_RANGE)) {
+                    (Some(k), Some(v)) => (k, v),
+                    _ => continue,
+                };
+                let mut modulators = ModList::defaults();
+                if let Some(global) = &inst_zones.global {
+                    override_modulators(&mut modulators, global.modulators);
+                }
+                override_modulators(&mut modulators, izone.modulators);
+                // Preset modulators are added to identical instrument ones
+                for m in &preset_modulators {
+                    match modulators.iter_mut().find(|x| x.is_identical(m)) {
+                        Some(x) => x.amount = x.amount.saturating_add(m.amount),
+                        None => modulators.push(m.clone()),
+                    }
+                }
+                let mut generators = vec![];
+                for oper in 0..NUM_GENERATORS {
+                    let amount = match oper {
+                        // Unused operators, and the ones that are part of the region itself
+                        14 | 18..=20 | 42 | 49 | 55 | 59 | 60 => continue,
+                        GEN_INSTRUMENT | GEN_SAMPLE_ID => continue,
+                        GEN_KEY_RANGE => [key_range.0, key_range.1],
+                        GEN_VEL_RANGE => [vel_range.0, vel_range.1],
+                        _ => {
+                            let value = amounts[oper].unwrap_or(default_amount(oper));
+                            match preset_amounts[oper] {
+                                // Preset generators are relative to the instrument ones
+                                Some(offset) if !is_instrument_only(oper) => {
+                                    i16::from_ne_bytes(value)
+                                        .saturating_add(i16::from_ne_bytes(offset))
+                                        .to_ne_bytes()
+                                }
+                                _ => value,
+                            }
+                        }
+                    };
+                    generators.push(parse_generator(oper as u16, amount));
+                }
+                regions.push(Region {
+                    preset_zone,
+                    instrument,
+                    sample,
+                    key_range,
+                    vel_range,
+                    generators,
+                    modulators,
+                });
+            }
+        }
+        regions
+    }
+
     pub fn safe_name(s: &str) -> String {
         s.chars()
             .map(|x| match x {
@@ -828,55 +1751,649 @@ impl SoundFont {
             })
             .collect()
     }
+    /// The resolution of the sample data, 24 if the font has an sm24 chunk, otherwise 16
+    pub fn bits_per_sample(&self) -> u16 {
+        if self.sample_data_24.is_some() {
+            24
+        } else {
+            16
+        }
+    }
+
+    /// Reads the sample points in [start, end) at the resolution given by `bits_per_sample`.
+    /// Points past the end of the sample data are left out.
+    pub fn read_sample_data(&self, start: u32, end: u32) -> Vec<i32> {
+        let end = (end as usize).min(self.sample_data.len() / 2);
+        let start = (start as usize).min(end);
+        let mut out = Vec::with_capacity(end.saturating_sub(start));
+        for ix in start..end {
+            let v = i16::from_le_bytes([self.sample_data[2 * ix], self.sample_data[2 * ix + 1]]);
+            if let Some(sm24) = &self.sample_data_24 {
+                out.push(((v as i32) << 8) | sm24[ix] as i32);
+            } else {
+                out.push(v as i32);
+            }
+        }
+        out
+    }
+
+    /// The peak level of the window of the sample relative to full scale, including the other
+    /// half if it is the left half of a stereo pair
+    pub fn peak_level(&self, ix: usize, window: &SampleWindow) -> f32 {
+        let mut windows = vec![*window];
+        if let Some((left, right)) = self.stereo_pair(ix) {
+            windows.push(window.rebase(&self.samples[left], &self.samples[right]));
+        }
+        let peak = windows
+            .iter()
+            .flat_map(|w| self.read_sample_data(w.start, w.end))
+            .map(|v| v.unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        let max = (1u32 << (self.bits_per_sample() - 1)) - 1;
+        peak as f32 / max as f32
+    }
+
+    /// Returns the (left, right) sample indices if the sample is one half of a linked stereo pair
+    pub fn stereo_pair(&self, ix: usize) -> Option<(usize, usize)> {
+        let sample = self.samples.get(ix)?;
+        let link = sample.sample_link as usize;
+        let other = self.samples.get(link)?;
+        match (sample.sample_type, other.sample_type) {
+            (SAMPLE_TYPE_LEFT, SAMPLE_TYPE_RIGHT) if other.sample_link as usize == ix => {
+                Some((ix, link))
+            }
+            (SAMPLE_TYPE_RIGHT, SAMPLE_TYPE_LEFT) if other.sample_link as usize == ix => {
+                Some((link, ix))
+            }
+            _ => None,
+        }
+    }
+
+    /// Saves the sample points in the window to a wav file, loop points are relative to the start of the window
     pub fn save_sample(
         &self,
         sample: &Sample,
-        loop_mode: LoopMode,
+        window: &SampleWindow,
+        options: &WavOptions,
         path: &Path,
     ) -> std::io::Result<()> {
-        info!("saving sample {} to {}", sample.name, path.display());
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
         match sample.sample_type {
-            1 | 2 | 4 => {
-                // TODO: maybe combine 2 and 4 to stereo sample?
-                info!(
-                    "saving sample {}, sample rate: {}",
-                    sample.name, sample.sample_rate
-                );
-                let (n, d) = sample.scale();
-                let sample_rate = n * sample.sample_rate / d;
-                let h = wav::Header::new(1, sample_rate);
-                let s = if loop_mode != LoopMode::NoLoop {
-                    println!("sample {} has loop {:?}", sample.name, loop_mode);
-                    Some(wav::SampleChunk::new(
-                        sample_rate,
-                        sample.start_loop - sample.start,
-                        sample.end_loop - sample.start,
-                    ))
-                } else {
-                    None
-                };
-                let mut out_file = fs::File::create(path)?;
-                let mut out = vec![];
-                out.reserve(self.sample_data.len() / 2);
-                let mut ix = sample.start * 2;
-                while ix + 2 <= sample.end * 2 {
-                    let low = self.sample_data[ix as usize];
-                    let high = self.sample_data[(ix + 1) as usize];
-                    out.push(i16::from_le_bytes([low, high]));
-                    ix += 2;
-                }
-                wav::write(h, &out, s, &mut out_file)?;
+            SAMPLE_TYPE_MONO | SAMPLE_TYPE_RIGHT | SAMPLE_TYPE_LEFT => {
+                self.write_wav(&[(sample, *window)], options, path)
             }
             _ => {
                 warn!(
                     "Unsupported sample type: {}, name: {}",
                     sample.sample_type, sample.name
                 );
+                Ok(())
+            }
+        }
+    }
+
+    /// Interleaves a linked left/right pair into one stereo wav. The window is for the left sample,
+    /// the same part of the right sample is used.
+    pub fn save_stereo_sample(
+        &self,
+        left: &Sample,
+        right: &Sample,
+        window: &SampleWindow,
+        options: &WavOptions,
+        path: &Path,
+    ) -> std::io::Result<()> {
+        if left.sample_rate != right.sample_rate {
+            warn!(
+                "Stereo pair {}/{} has different sample rates, using {}",
+                left.name, right.name, left.sample_rate
+            );
+        }
+        let right_window = window.rebase(left, right);
+        self.write_wav(&[(left, *window), (right, right_window)], options, path)
+    }
+
+    fn write_wav(
+        &self,
+        channels: &[(&Sample, SampleWindow)],
+        options: &WavOptions,
+        path: &Path,
+    ) -> std::io::Result<()> {
+        let (sample, window) = channels[0];
+        info!("saving sample {} to {}", sample.name, path.display());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        info!(
+            "saving sample {}, sample rate: {}, channels: {}",
+            sample.name,
+            sample.sample_rate,
+            channels.len()
+        );
+        let sample_rate = options.sample_rate;
+        let h = wav::Header::with_bits(channels.len() as u16, sample_rate, self.bits_per_sample());
+        let s = if options.loop_mode != LoopMode::NoLoop {
+            println!("sample {} has loop {:?}", sample.name, options.loop_mode);
+            Some(wav::SampleChunk::new(
+                sample_rate,
+                resample::position(
+                    window.start_loop - window.start,
+                    sample.sample_rate,
+                    sample_rate,
+                ),
+                resample::position(
+                    window.end_loop - window.start,
+                    sample.sample_rate,
+                    sample_rate,
+                ),
+            ))
+        } else {
+            None
+        };
+        // Full scale for the bits per sample
+        let max = (1 << (self.bits_per_sample() - 1)) - 1;
+        let data: Vec<Vec<i32>> = channels
+            .iter()
+            .map(|(_, w)| {
+                let mut data = self.read_sample_data(w.start, w.end);
+                if options.gain != 1.0 {
+                    for v in data.iter_mut() {
+                        *v = (*v as f32 * options.gain).round() as i32;
+                    }
+                }
+                let mut data = resample::resample(&data, sample.sample_rate, sample_rate);
+                // Resampling can overshoot a little
+                for v in data.iter_mut() {
+                    *v = (*v).clamp(-max - 1, max);
+                }
+                data
+            })
+            .collect();
+        // Pad the shorter channel with silence if the halves differ in length
+        let len = data.iter().map(|d| d.len()).max().unwrap_or(0);
+        let mut out = Vec::with_capacity(len * data.len());
+        for ix in 0..len {
+            for d in &data {
+                out.push(d.get(ix).copied().unwrap_or(0));
             }
         }
+        let mut out_file = fs::File::create(path)?;
+        wav::write(h, &out, s, &mut out_file)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> Vec<u8> {
+        let mut v = s.as_bytes().to_vec();
+        v.resize(20, 0);
+        v
+    }
+
+    fn phdr(presets: &[(&str, u16)]) -> Vec<u8> {
+        let mut v = vec![];
+        for (n, bag) in presets {
+            v.extend(name(n));
+            v.extend([0u8; 4]);
+            v.extend(bag.to_le_bytes());
+            v.extend([0u8; 12]);
+        }
+        v
+    }
+
+    fn inst(instruments: &[(&str, u16)]) -> Vec<u8> {
+        let mut v = vec![];
+        for (n, bag) in instruments {
+            v.extend(name(n));
+            v.extend(bag.to_le_bytes());
+        }
+        v
+    }
+
+    fn bags(bags: &[(u16, u16)]) -> Vec<u8> {
+        let mut v = vec![];
+        for (gen_ndx, mod_ndx) in bags {
+            v.extend(gen_ndx.to_le_bytes());
+            v.extend(mod_ndx.to_le_bytes());
+        }
+        v
+    }
+
+    fn gens(gens: &[(u16, i16)]) -> Vec<u8> {
+        let mut v = vec![];
+        for (oper, amount) in gens {
+            v.extend(oper.to_le_bytes());
+            v.extend(amount.to_le_bytes());
+        }
+        v
+    }
+
+    fn shdr(samples: &[(&str, u32, u32)]) -> Vec<u8> {
+        let mut v = vec![];
+        for (n, start, end) in samples {
+            v.extend(name(n));
+            v.extend(start.to_le_bytes());
+            v.extend(end.to_le_bytes());
+            v.extend(start.to_le_bytes());
+            v.extend(end.to_le_bytes());
+            v.extend(44100u32.to_le_bytes());
+            v.extend([60u8, 0]);
+            v.extend([0u8, 0]);
+            v.extend(1u16.to_le_bytes());
+        }
+        v
+    }
+
+    fn list(id: &[u8; 4], chunks: Vec<(&[u8; 4], Vec<u8>)>) -> riff::ChunkContents {
+        riff::ChunkContents::Children(
+            riff::LIST_ID.clone(),
+            riff::ChunkId { value: *id },
+            chunks
+                .into_iter()
+                .map(|(id, data)| riff::ChunkContents::Data(riff::ChunkId { value: *id }, data))
+                .collect(),
+        )
+    }
+
+    /// One preset with one instrument with one sample
+    fn pdta() -> Vec<(&'static [u8; 4], Vec<u8>)> {
+        vec![
+            (&PHDR, phdr(&[("Piano", 0), ("EOP", 1)])),
+            (&PBAG, bags(&[(0, 0), (1, 0)])),
+            (&PMOD, vec![0; 10]),
+            (&PGEN, gens(&[(41, 0), (0, 0)])),
+            (&INST, inst(&[("Piano", 0), ("EOI", 1)])),
+            (&IBAG, bags(&[(0, 0), (1, 0)])),
+            (&IMOD, vec![0; 10]),
+            (&IGEN, gens(&[(53, 0), (0, 0)])),
+            (&SHDR, shdr(&[("C4", 0, 8), ("EOS", 0, 0)])),
+        ]
+    }
+
+    fn build(pdta: Vec<(&[u8; 4], Vec<u8>)>) -> Cursor<Vec<u8>> {
+        build_with(
+            vec![(&IFIL, vec![2, 0, 1, 0])],
+            vec![(&SMPL, vec![0; 2 * 8])],
+            pdta,
+        )
+    }
+
+    fn build_with(
+        info: Vec<(&[u8; 4], Vec<u8>)>,
+        sdta: Vec<(&[u8; 4], Vec<u8>)>,
+        pdta: Vec<(&[u8; 4], Vec<u8>)>,
+    ) -> Cursor<Vec<u8>> {
+        let sfbk = riff::ChunkContents::Children(
+            riff::RIFF_ID.clone(),
+            riff::ChunkId { value: *b"sfbk" },
+            vec![list(b"INFO", info), list(&SDTA, sdta), list(b"pdta", pdta)],
+        );
+        let mut cursor = Cursor::new(vec![]);
+        sfbk.write(&mut cursor).unwrap();
+        cursor
+    }
+
+    #[test]
+    fn test_parse() {
+        let sf = SoundFont::parse_soundfont(&mut build(pdta())).unwrap();
+        assert_eq!(sf.presets.len(), 2);
+        assert_eq!(sf.presets[0].name, "Piano");
+        assert_eq!(sf.samples[0].end, 8);
+        assert_eq!(sf.igens[0], Generator::SampleID(0));
+        assert_eq!(sf.info.version, Version { major: 2, minor: 1 });
+    }
+
+    #[test]
+    fn test_info() {
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![
+                (&IFIL, vec![2, 0, 4, 0]),
+                (&INAM, b"My Font\0".to_vec()),
+                (&ICOP, b"Do not redistribute\0\0".to_vec()),
+                (&ICMT, vec![b'f', 0xF6, b'o', 0]),
+            ],
+            vec![(&SMPL, vec![0; 2 * 8])],
+            pdta(),
+        ))
+        .unwrap();
+        assert_eq!(sf.info.version, Version { major: 2, minor: 4 });
+        assert_eq!(sf.info.name.as_deref(), Some("My Font"));
+        assert_eq!(sf.info.comments.as_deref(), Some("föo"));
+        assert_eq!(sf.info.author, None);
+        assert!(sf.info.forbids_redistribution());
+    }
+
+    #[test]
+    fn test_sm24() {
+        let smpl: Vec<u8> = (0..8i16).flat_map(|x| (-x).to_le_bytes()).collect();
+        let sm24: Vec<u8> = (0..8).collect();
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![(&IFIL, vec![2, 0, 4, 0])],
+            vec![(&SMPL, smpl.clone()), (&SM24, sm24.clone())],
+            pdta(),
+        ))
+        .unwrap();
+        assert_eq!(sf.bits_per_sample(), 24);
+        assert_eq!(sf.read_sample_data(2, 4), vec![-2 * 256 + 2, -3 * 256 + 3]);
+
+        // Not supported before 2.04
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![(&IFIL, vec![2, 0, 1, 0])],
+            vec![(&SMPL, smpl), (&SM24, sm24)],
+            pdta(),
+        ))
+        .unwrap();
+        assert_eq!(sf.bits_per_sample(), 16);
+        assert_eq!(sf.read_sample_data(2, 4), vec![-2, -3]);
+    }
+
+    #[test]
+    fn test_override_modulators() {
+        let mut modulators = ModList::defaults();
+        override_modulators(
+            &mut modulators,
+            &[
+                ModList::new(0x0502, 48, 480, 0, 0),
+                ModList::new(0x0081, 8, 100, 0, 0),
+            ],
+        );
+        assert_eq!(modulators.len(), 11);
+        assert_eq!(modulators[0].amount, 480);
+        assert_eq!(
+            modulators[10].src_oper.index,
+            SourceEnumeratorController::MidiCC(1)
+        );
+        assert_eq!(
+            modulators[10].dest_oper,
+            DestOper::Generator(Generator::InitialFilterFc(0))
+        );
+    }
+
+    #[test]
+    fn test_stereo_pair() {
+        let mut samples = shdr(&[("L", 0, 4), ("R", 4, 8), ("Mono", 0, 8), ("EOS", 0, 0)]);
+        // Link L (left) and R (right) to each other
+        samples[42..46].copy_from_slice(&[1, 0, 4, 0]);
+        samples[46 + 42..46 + 46].copy_from_slice(&[0, 0, 2, 0]);
+        let mut pdta = pdta();
+        pdta[8].1 = samples;
+        let smpl: Vec<u8> = (0..8i16).flat_map(|x| x.to_le_bytes()).collect();
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![(&IFIL, vec![2, 0, 1, 0])],
+            vec![(&SMPL, smpl)],
+            pdta,
+        ))
+        .unwrap();
+        assert_eq!(sf.stereo_pair(0), Some((0, 1)));
+        assert_eq!(sf.stereo_pair(1), Some((0, 1)));
+        assert_eq!(sf.stereo_pair(2), None);
+
+        let path = std::env::temp_dir().join(format!("stereo_{}.wav", std::process::id()));
+        let window = SampleWindow::of(&sf.samples[0]);
+        sf.save_stereo_sample(
+            &sf.samples[0],
+            &sf.samples[1],
+            &window,
+            &WavOptions {
+                loop_mode: LoopMode::NoLoop,
+                gain: 1.0,
+                sample_rate: sf.samples[0].sample_rate,
+            },
+            &path,
+        )
+        .unwrap();
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        // Channel count in the fmt chunk
+        assert_eq!(&wav[22..24], &[2, 0]);
+        let data: Vec<i16> = wav[wav.len() - 16..]
+            .chunks(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]))
+            .collect();
+        assert_eq!(data, vec![0, 4, 1, 5, 2, 6, 3, 7]);
+    }
+
+    #[test]
+    fn test_stereo_pair_shorter_right() {
+        // The right half is shorter than the left, and followed by another sample
+        let mut samples = shdr(&[("L", 0, 4), ("R", 4, 6), ("Mono", 6, 8), ("EOS", 0, 0)]);
+        samples[42..46].copy_from_slice(&[1, 0, 4, 0]);
+        samples[46 + 42..46 + 46].copy_from_slice(&[0, 0, 2, 0]);
+        let mut pdta = pdta();
+        pdta[8].1 = samples;
+        let smpl: Vec<u8> = (0..8i16).flat_map(|x| x.to_le_bytes()).collect();
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![(&IFIL, vec![2, 0, 1, 0])],
+            vec![(&SMPL, smpl)],
+            pdta,
+        ))
+        .unwrap();
+        let window = SampleWindow::of(&sf.samples[0]);
+        assert_eq!(
+            window.rebase(&sf.samples[0], &sf.samples[1]),
+            SampleWindow {
+                start: 4,
+                end: 6,
+                start_loop: 4,
+                end_loop: 6,
+            }
+        );
+
+        let path = std::env::temp_dir().join(format!("stereo_short_{}.wav", std::process::id()));
+        sf.save_stereo_sample(
+            &sf.samples[0],
+            &sf.samples[1],
+            &window,
+            &WavOptions {
+                loop_mode: LoopMode::NoLoop,
+                gain: 1.0,
+                sample_rate: sf.samples[0].sample_rate,
+            },
+            &path,
+        )
+        .unwrap();
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let data: Vec<i16> = wav[wav.len() - 16..]
+            .chunks(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]))
+            .collect();
+        // The right channel is padded with silence instead of reading the mono sample
+        assert_eq!(data, vec![0, 4, 1, 5, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn test_missing_chunk() {
+        let pdta = pdta().into_iter().filter(|(id, _)| **id != PHDR).collect();
+        let err = SoundFont::parse_soundfont(&mut build(pdta)).err().unwrap();
+        assert!(matches!(err, SoundFontError::MissingChunk("phdr")));
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let mut pdta = pdta();
+        pdta[3].1.pop();
+        let err = SoundFont::parse_soundfont(&mut build(pdta)).err().unwrap();
+        assert!(matches!(
+            err,
+            SoundFontError::TruncatedRecord { chunk: "pgen", .. }
+        ));
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let mut data = build(pdta()).into_inner();
+        data.truncate(data.len() - 10);
+        let err = SoundFont::parse_soundfont(&mut Cursor::new(data))
+            .err()
+            .unwrap();
+        assert!(matches!(err, SoundFontError::TruncatedChunk { .. }));
+    }
+
+    #[test]
+    fn test_index_out_of_range() {
+        let mut pdta = pdta();
+        pdta[7].1 = gens(&[(53, 5), (0, 0)]);
+        let err = SoundFont::parse_soundfont(&mut build(pdta)).err().unwrap();
+        assert!(matches!(
+            err,
+            SoundFontError::IndexOutOfRange {
+                table: "shdr",
+                index: 5,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_invalid_vorbis() {
+        let mut pdta = pdta();
+        // Mark the sample as compressed
+        pdta[8].1[44] = 0x11;
+        let err = SoundFont::parse_soundfont(&mut build(pdta)).err().unwrap();
+        assert!(matches!(err, SoundFontError::Vorbis { .. }));
+    }
+
+    #[test]
+    fn test_uncompressed_sample_out_of_range() {
+        let mut pdta = pdta();
+        // An uncompressed sample past the end of the 8 samples of data, next to a compressed one
+        pdta[8].1 = shdr(&[("C4", 10, 12), ("Ogg", 0, 8), ("EOS", 0, 0)]);
+        pdta[8].1[46 + 44] = 0x11;
+        let err = SoundFont::parse_soundfont(&mut build(pdta)).err().unwrap();
+        assert!(matches!(
+            err,
+            SoundFontError::IndexOutOfRange {
+                table: "smpl",
+                index: 24,
+                len: 16,
+            }
+        ));
+    }
+
+    fn range(low: u8, high: u8) -> i16 {
+        i16::from_le_bytes([low, high])
+    }
+
+    #[test]
+    fn test_regions() {
+        let mut pdta = pdta();
+        pdta[0].1 = phdr(&[("Piano", 0), ("EOP", 2)]);
+        pdta[1].1 = bags(&[(0, 0), (2, 0), (4, 0)]);
+        // A global zone with key range and tuning, and a zone with attenuation
+        pdta[3].1 = gens(&[(43, range(0, 100)), (51, 2), (48, 10), (41, 0), (0, 0)]);
+        pdta[4].1 = inst(&[("Piano", 0), ("EOI", 3)]);
+        pdta[5].1 = bags(&[(0, 0), (2, 0), (4, 0), (7, 0)]);
+        // A global zone with attack and tuning, and two zones where the second overrides the attack
+        pdta[7].1 = gens(&[
+            (34, -1200),
+            (51, 1),
+            (43, range(40, 80)),
+            (53, 0),
+            (43, range(81, 127)),
+            (34, 0),
+            (53, 0),
+            (0, 0),
+        ]);
+        let sf = SoundFont::parse_soundfont(&mut build(pdta)).unwrap();
+        let regions = sf.regions(0);
+        assert_eq!(regions.len(), 2);
+        let get = |r: &Region, oper: u16| {
+            *r.generators
+                .iter()
+                .find(|g| g.oper() == Some(oper))
+                .unwrap()
+        };
+        assert_eq!(regions[0].key_range, (40, 80));
+        assert_eq!(regions[1].key_range, (81, 100));
+        assert_eq!(regions[0].vel_range, (0, 127));
+        assert_eq!(get(&regions[0], 34), Generator::AttackVolEnv(-1200));
+        assert_eq!(get(&regions[1], 34), Generator::AttackVolEnv(0));
+        for region in &regions {
+            assert_eq!(region.sample, 0);
+            assert_eq!(get(region, 51), Generator::CoarseTune(3));
+            assert_eq!(get(region, 48), Generator::InitialAttenuation(10));
+            assert_eq!(get(region, 8), Generator::InitialFilterFc(13500));
+            assert_eq!(get(region, 58), Generator::OverridingRootKey(-1));
+        }
+    }
+
+    #[test]
+    fn test_sample_window() {
+        let mut pdta = pdta();
+        pdta[8].1 = shdr(&[("Long", 100, 70000), ("EOS", 0, 0)]);
+        // Fine and coarse offsets on the start and end, and the loop end
+        pdta[5].1 = bags(&[(0, 0), (6, 0)]);
+        pdta[7].1 = gens(&[
+            (0, 10),
+            (4, 1),
+            (1, -20),
+            (3, -5),
+            (50, -1),
+            (53, 0),
+            (0, 0),
+        ]);
+        let sf = SoundFont::parse_soundfont(&mut build_with(
+            vec![(&IFIL, vec![2, 0, 1, 0])],
+            vec![(&SMPL, vec![0; 2 * 70046])],
+            pdta,
+        ))
+        .unwrap();
+        let regions = sf.regions(0);
+        let window = regions[0].sample_window(&sf.samples[0]);
+        assert_eq!(
+            window,
+            SampleWindow {
+                start: 100 + 10 + 32768,
+                end: 70000 - 20,
+                start_loop: 100 + 10 + 32768,
+                end_loop: 70000 - 5 - 32768,
+            }
+        );
+        assert_eq!(
+            SampleWindow::of(&sf.samples[0]),
+            SampleWindow {
+                start: 100,
+                end: 70000,
+                start_loop: 100,
+                end_loop: 70000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_global_zones() {
+        let mut pdta = pdta();
+        pdta[4].1 = inst(&[("Piano", 0), ("EOI", 3)]);
+        pdta[5].1 = bags(&[(0, 0), (1, 0), (3, 0), (4, 0)]);
+        // A global zone, a zone with a sample, and a zone without one that should be ignored
+        pdta[7].1 = gens(&[(34, -1200), (43, range(0, 60)), (53, 0), (51, 1), (0, 0)]);
+        let sf = SoundFont::parse_soundfont(&mut build(pdta)).unwrap();
+        let zones = sf.instrument_zones(0);
+        assert_eq!(
+            zones.global.unwrap().generators,
+            &[Generator::AttackVolEnv(-1200)]
+        );
+        assert_eq!(zones.zones.len(), 1);
+        assert_eq!(zones.zones[0].generators[1], Generator::SampleID(0));
+        let regions = sf.regions(0);
+        assert_eq!(regions.len(), 1);
+        assert!(regions[0]
+            .generators
+            .contains(&Generator::AttackVolEnv(-1200)));
+        let zones = sf.preset_zones(0);
+        assert!(zones.global.is_none());
+        assert_eq!(zones.zones.len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_modulator() {
+        let m = parse_modulator(0x7c00 | 0x42);
+        assert_eq!(m.continuity, SourceType::Unknown(0x1f));
+        assert_eq!(m.index, SourceEnumeratorController::Unknown(0x42));
+        assert!(matches!(parse_dest_oper(14), DestOper::Unknown(14)));
+        assert_eq!(parse_transform(7), ModularTransform::Unknown(7));
+    }
+}
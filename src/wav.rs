@@ -14,7 +14,12 @@ pub struct Header {
 impl Header {
     #[must_use]
     pub fn new(channel_count: u16, sampling_rate: u32) -> Header {
-        let bits_per_sample = 16;
+        Header::with_bits(channel_count, sampling_rate, 16)
+    }
+
+    /// Creates a header for 16 or 24 bit PCM
+    #[must_use]
+    pub fn with_bits(channel_count: u16, sampling_rate: u32, bits_per_sample: u16) -> Header {
         Header {
             audio_format: WAV_FORMAT_PCM,
             channel_count,
@@ -115,8 +120,7 @@ impl SampleChunk {
 
 impl From<&SampleChunk> for Vec<u8> {
     fn from(s: &SampleChunk) -> Self {
-        let mut v = vec![];
-        v.resize(0x2c - 8, 0);
+        let mut v = vec![0; 0x2c - 8];
 
         let sample_period = 1_000_000_000 / s.sample_rate;
         v[8..12].copy_from_slice(&sample_period.to_le_bytes());
@@ -135,9 +139,10 @@ impl From<SampleChunk> for Vec<u8> {
     }
 }
 
+/// Writes a wav file, the sample points in `track` are written with the resolution given by `header.bits_per_sample`
 pub fn write<W>(
     header: Header,
-    track: &[i16],
+    track: &[i32],
     sample: Option<SampleChunk>,
     writer: &mut W,
 ) -> std::io::Result<()>
@@ -168,10 +173,10 @@ where
         chunks.push(s_dat);
     }
 
-    let mut d_vec: Vec<u8> = Vec::new();
-    d_vec.reserve(2 * track.len());
+    let bytes_per_point = (header.bits_per_sample >> 3) as usize;
+    let mut d_vec: Vec<u8> = Vec::with_capacity(bytes_per_point * track.len());
     for sample in track {
-        d_vec.extend(sample.to_le_bytes());
+        d_vec.extend(&sample.to_le_bytes()[0..bytes_per_point]);
     }
     let d_dat = riff::ChunkContents::Data(DATA_ID, d_vec);
     chunks.push(d_dat);